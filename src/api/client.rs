@@ -1,10 +1,17 @@
-use reqwest::{Client as HttpClient, StatusCode};
+use reqwest::{Client as HttpClient, Method, Response, StatusCode, header::RETRY_AFTER};
 use serde_json::json;
-use std::time::Duration;
+use std::{
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::time::sleep;
+use crate::api::docs::{self, MarvinDb, TypedDoc, Versioned};
 use crate::api::error::ApiError;
+use crate::api::rate_limit::{MARVIN_RATE_LIMITER, RateLimiter};
 use crate::api::requests::*;
 use crate::api::responses::*;
+use crate::retry::{jitter, retry_after};
 use crate::models::{
     tasks::{Task, ProjectOrCategory},
     calendars::Event,
@@ -16,6 +23,25 @@ use crate::models::{
 const MAX_RETRIES: u32 = 5;
 /// Initial backoff delay in seconds
 const INITIAL_BACKOFF_SECS: u64 = 2;
+/// Default request timeout in seconds, unless `MARVIN_TIMEOUT_SECS` is set
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+/// Timeout for requests that return everything of a kind, e.g. all categories
+const LISTING_TIMEOUT: Duration = Duration::from_secs(90);
+/// POST endpoints that only read, so they're safe to send again
+const READ_ONLY_POSTS: &[&str] = &["tracks"];
+
+fn default_timeout() -> Duration {
+    let secs = env::var("MARVIN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Network problems that may well be gone on the next attempt.
+fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect()
+}
 
 /// The default base URL for Marvin's API.
 pub const MARVIN_BASE_URL: &str = "https://serv.amazingmarvin.com/api";
@@ -29,6 +55,8 @@ pub struct MarvinClient {
     base_url: String,
     api_token: Option<String>,
     full_access_token: Option<String>,
+    /// Per-endpoint request budgets, shared by all clients
+    limiter: Arc<RateLimiter>,
 }
 
 impl MarvinClient {
@@ -43,6 +71,7 @@ impl MarvinClient {
             base_url: MARVIN_BASE_URL.to_string(),
             api_token,
             full_access_token,
+            limiter: MARVIN_RATE_LIMITER.clone(),
         }
     }
 
@@ -56,21 +85,33 @@ impl MarvinClient {
     // Utility
     //--------------------------------------------------------------------------
 
-    async fn get<T>(&self, endpoint: &str, query: Option<&[(&str, &str)]>) -> Result<T, ApiError>
-    where
-        T: serde::de::DeserializeOwned,
-    {
+    /// Send one request to `endpoint` and return the successful response.
+    /// - waits for the endpoint's rate limit budget before every attempt
+    /// - retries 429s, after `Retry-After` if Marvin sends one (plus jitter)
+    /// - retries idempotent requests after network errors and timeouts
+    /// - puts the body of failed responses into the error
+    async fn execute(
+        &self,
+        method: Method,
+        endpoint: &str,
+        query: Option<&[(&str, &str)]>,
+        body: Option<&serde_json::Value>,
+        timeout: Option<Duration>,
+    ) -> Result<Response, ApiError> {
         let url = format!("{}/{}", self.base_url, endpoint);
+        let idempotent = method == Method::GET || READ_ONLY_POSTS.contains(&endpoint);
+        let timeout = timeout.unwrap_or_else(default_timeout);
         let mut retries = 0;
-        let mut backoff_secs = INITIAL_BACKOFF_SECS;
+        let mut backoff = Duration::from_secs(INITIAL_BACKOFF_SECS);
 
         loop {
-            let mut req = self.http.get(&url);
-
+            let mut req = self.http.request(method.clone(), &url).timeout(timeout);
             if let Some(q) = query {
                 req = req.query(q);
             }
-
+            if let Some(body) = body {
+                req = req.json(body);
+            }
             // Use whichever token is available, typically the API token
             if let Some(ref token) = self.api_token {
                 req = req.header("X-API-Token", token);
@@ -79,79 +120,77 @@ impl MarvinClient {
                 req = req.header("X-Full-Access-Token", token);
             }
 
-            let resp = req.send().await?;
-            println!("{:#?}", resp);
-
-            if resp.status() == StatusCode::TOO_MANY_REQUESTS {
-                if retries >= MAX_RETRIES {
-                    println!("[MARVIN] Rate limited, max retries ({}) exceeded", MAX_RETRIES);
-                    return Err(ApiError::StatusCodeError(resp.status()));
+            self.limiter.acquire(endpoint).await;
+            let started = Instant::now();
+            let resp = match req.send().await {
+                Ok(resp) => resp,
+                Err(err) if idempotent && retries < MAX_RETRIES && is_transient(&err) => {
+                    let delay = backoff + jitter(backoff);
+                    tracing::warn!(
+                        %method, endpoint, error = %err, retry = retries + 1, ?delay,
+                        "Marvin request failed, retrying"
+                    );
+                    sleep(delay).await;
+                    retries += 1;
+                    backoff *= 2;
+                    continue;
                 }
-                println!(
-                    "[MARVIN] Rate limited (429), retry {}/{} after {}s backoff",
-                    retries + 1,
-                    MAX_RETRIES,
-                    backoff_secs
+                Err(err) => return Err(err.into()),
+            };
+            let status = resp.status();
+            let elapsed_ms = started.elapsed().as_millis() as u64;
+            tracing::debug!(%method, endpoint, %status, elapsed_ms, "Marvin response");
+
+            if status == StatusCode::TOO_MANY_REQUESTS && retries < MAX_RETRIES {
+                let delay = retry_after(&resp, &[RETRY_AFTER.as_str()]).unwrap_or(backoff);
+                let delay = delay + jitter(delay);
+                tracing::warn!(
+                    %method, endpoint, retry = retries + 1, max_retries = MAX_RETRIES, ?delay,
+                    "Marvin rate limited the request"
                 );
-                sleep(Duration::from_secs(backoff_secs)).await;
+                sleep(delay).await;
                 retries += 1;
-                backoff_secs *= 2; // Exponential backoff
+                backoff *= 2; // Exponential backoff
                 continue;
             }
 
-            if !resp.status().is_success() {
-                return Err(ApiError::StatusCodeError(resp.status()));
+            if !status.is_success() {
+                let body = resp.text().await.unwrap_or_default();
+                tracing::warn!(%method, endpoint, %status, body = %body, "Marvin request failed");
+                return Err(ApiError::StatusCodeError { status, body });
             }
-            let data = resp.json::<T>().await?;
-            return Ok(data);
+            return Ok(resp);
         }
     }
 
+    async fn get<T>(&self, endpoint: &str, query: Option<&[(&str, &str)]>) -> Result<T, ApiError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        self.get_with_timeout(endpoint, query, None).await
+    }
+
+    async fn get_with_timeout<T>(
+        &self,
+        endpoint: &str,
+        query: Option<&[(&str, &str)]>,
+        timeout: Option<Duration>,
+    ) -> Result<T, ApiError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let resp = self.execute(Method::GET, endpoint, query, None, timeout).await?;
+        Ok(resp.json::<T>().await?)
+    }
+
     async fn post_json<Req, Res>(&self, endpoint: &str, body: &Req) -> Result<Res, ApiError>
     where
         Req: serde::Serialize,
         Res: serde::de::DeserializeOwned,
     {
-        let url = format!("{}/{}", self.base_url, endpoint);
-        let mut retries = 0;
-        let mut backoff_secs = INITIAL_BACKOFF_SECS;
-
-        loop {
-            let mut req = self.http.post(&url).json(body);
-
-            if let Some(ref token) = self.api_token {
-                req = req.header("X-API-Token", token);
-            }
-            if let Some(ref token) = self.full_access_token {
-                req = req.header("X-Full-Access-Token", token);
-            }
-
-            let resp = req.send().await?;
-            println!("{:#?}", resp);
-
-            if resp.status() == StatusCode::TOO_MANY_REQUESTS {
-                if retries >= MAX_RETRIES {
-                    println!("[MARVIN] Rate limited, max retries ({}) exceeded", MAX_RETRIES);
-                    return Err(ApiError::StatusCodeError(resp.status()));
-                }
-                println!(
-                    "[MARVIN] Rate limited (429), retry {}/{} after {}s backoff",
-                    retries + 1,
-                    MAX_RETRIES,
-                    backoff_secs
-                );
-                sleep(Duration::from_secs(backoff_secs)).await;
-                retries += 1;
-                backoff_secs *= 2;
-                continue;
-            }
-
-            if !resp.status().is_success() {
-                return Err(ApiError::StatusCodeError(resp.status()));
-            }
-            let data = resp.json::<Res>().await?;
-            return Ok(data);
-        }
+        let body = serde_json::to_value(body).map_err(|err| ApiError::DataError(err.to_string()))?;
+        let resp = self.execute(Method::POST, endpoint, None, Some(&body), None).await?;
+        Ok(resp.json::<Res>().await?)
     }
 
     // Sometimes responses are just "OK" or a raw string. We'll have a variant:
@@ -159,46 +198,9 @@ impl MarvinClient {
     where
         Req: serde::Serialize,
     {
-        let url = format!("{}/{}", self.base_url, endpoint);
-        let mut retries = 0;
-        let mut backoff_secs = INITIAL_BACKOFF_SECS;
-
-        loop {
-            let mut req = self.http.post(&url).json(body);
-
-            if let Some(ref token) = self.api_token {
-                req = req.header("X-API-Token", token);
-            }
-            if let Some(ref token) = self.full_access_token {
-                req = req.header("X-Full-Access-Token", token);
-            }
-
-            let resp = req.send().await?;
-            println!("{:#?}", resp);
-
-            if resp.status() == StatusCode::TOO_MANY_REQUESTS {
-                if retries >= MAX_RETRIES {
-                    println!("[MARVIN] Rate limited, max retries ({}) exceeded", MAX_RETRIES);
-                    return Err(ApiError::StatusCodeError(resp.status()));
-                }
-                println!(
-                    "[MARVIN] Rate limited (429), retry {}/{} after {}s backoff",
-                    retries + 1,
-                    MAX_RETRIES,
-                    backoff_secs
-                );
-                sleep(Duration::from_secs(backoff_secs)).await;
-                retries += 1;
-                backoff_secs *= 2;
-                continue;
-            }
-
-            if !resp.status().is_success() {
-                return Err(ApiError::StatusCodeError(resp.status()));
-            }
-            let text = resp.text().await?;
-            return Ok(text);
-        }
+        let body = serde_json::to_value(body).map_err(|err| ApiError::DataError(err.to_string()))?;
+        let resp = self.execute(Method::POST, endpoint, None, Some(&body), None).await?;
+        Ok(resp.text().await?)
     }
 
     //--------------------------------------------------------------------------
@@ -245,6 +247,44 @@ impl MarvinClient {
         self.post_json("doc/update", req).await
     }
 
+    /// Read any doc and parse it into the model for its `db`
+    pub async fn read_typed_doc(&self, doc_id: &str) -> Result<Versioned<TypedDoc>, ApiError> {
+        TypedDoc::from_doc(self.read_doc(doc_id).await?)
+    }
+
+    /// Read a doc that has to be a `T`, e.g. a Task or ProjectOrCategory
+    pub async fn read_doc_as<T: MarvinDb>(&self, doc_id: &str) -> Result<Versioned<T>, ApiError> {
+        let doc = self.read_doc(doc_id).await?;
+        Ok(Versioned { doc: docs::parse(&doc)?, rev: doc.rev })
+    }
+
+    /// Apply `setters` to a `T` and return it as Marvin saved it.
+    /// With `rev`, the doc is read first and left alone if it has changed since
+    /// (ApiError::Conflict). This is best-effort: /doc/update takes no revision, so
+    /// a change that lands between the check and the update is still overwritten.
+    pub async fn update_doc_as<T: MarvinDb>(
+        &self,
+        doc_id: &str,
+        rev: Option<&str>,
+        setters: Vec<DocSetter>,
+    ) -> Result<Versioned<T>, ApiError> {
+        let setters = docs::prepare_setters(setters, chrono::Utc::now().timestamp_millis())?;
+        if let Some(expected) = rev {
+            let current = self.read_doc(doc_id).await?;
+            docs::parse::<T>(&current)?;
+            if current.rev.as_deref() != Some(expected) {
+                return Err(ApiError::Conflict {
+                    id: doc_id.to_string(),
+                    expected: expected.to_string(),
+                    found: current.rev.unwrap_or_default(),
+                });
+            }
+        }
+        let req = UpdateDocRequest { item_id: doc_id.to_string(), setters };
+        let doc = self.update_doc(&req).await?;
+        Ok(Versioned { doc: docs::parse(&doc)?, rev: doc.rev })
+    }
+
     /// Create any doc (requires full-access token) via POST /api/doc/create
     pub async fn create_doc(&self, req: &CreateDocRequest) -> Result<MarvinDoc, ApiError> {
         self.post_json("doc/create", req).await
@@ -277,6 +317,17 @@ impl MarvinClient {
         }
     }
 
+    /// Get tasks/projects completed on a given day: GET /api/doneItems
+    /// You can pass a date=YYYY-MM-DD as a query param; defaults to today.
+    pub async fn get_done_items(&self, date: Option<&str>) -> Result<Vec<Task>, ApiError> {
+        let query = date.map(|d| [("date", d)]);
+        if let Some(q) = query {
+            self.get("doneItems", Some(&q)).await
+        } else {
+            self.get("doneItems", None).await
+        }
+    }
+
     /// Get tasks/projects due by a certain date: GET /api/dueItems
     pub async fn get_due_items(&self, by: Option<&str>) -> Result<Vec<Task>, ApiError> {
         let query = by.map(|d| [("by", d)]);
@@ -299,7 +350,7 @@ impl MarvinClient {
 
     /// Get a list of all categories: GET /api/categories
     pub async fn get_categories(&self) -> Result<CategoriesResponse, ApiError> {
-        self.get("categories", None).await
+        self.get_with_timeout("categories", None, Some(LISTING_TIMEOUT)).await
     }
 
     /// Get a list of all labels: GET /api/labels
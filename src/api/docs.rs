@@ -0,0 +1,212 @@
+use serde::de::DeserializeOwned;
+
+use crate::api::error::ApiError;
+use crate::api::requests::{DocSetter, MarvinDoc};
+use crate::models::{
+    habits::Habit,
+    labels::Label,
+    tasks::{Goal, ProjectOrCategory, RecurringTask, SavedItem, Task},
+};
+
+/// Model structs that are stored as docs in one of Marvin's databases.
+pub trait MarvinDb: DeserializeOwned {
+    /// The doc's `db` field
+    const DB: &'static str;
+}
+
+impl MarvinDb for Task {
+    const DB: &'static str = "Tasks";
+}
+
+impl MarvinDb for ProjectOrCategory {
+    const DB: &'static str = "Categories";
+}
+
+impl MarvinDb for Habit {
+    const DB: &'static str = "Habits";
+}
+
+impl MarvinDb for Goal {
+    const DB: &'static str = "Goals";
+}
+
+impl MarvinDb for Label {
+    const DB: &'static str = "Labels";
+}
+
+impl MarvinDb for RecurringTask {
+    const DB: &'static str = "RecurringTasks";
+}
+
+impl MarvinDb for SavedItem {
+    const DB: &'static str = "SavedItems";
+}
+
+/// A doc with the revision it was read at. Models don't carry `_rev`, so it's kept
+/// next to them for the next update.
+#[derive(Debug, Clone)]
+pub struct Versioned<T> {
+    pub rev: Option<String>,
+    pub doc: T,
+}
+
+/// Any Marvin doc, parsed into the model for its `db`.
+#[derive(Debug, Clone)]
+pub enum TypedDoc {
+    Task(Box<Task>),
+    Category(ProjectOrCategory),
+    Habit(Habit),
+    Goal(Goal),
+    Label(Label),
+    RecurringTask(RecurringTask),
+    SavedItem(SavedItem),
+    /// A database without a model; fields stay in `extra`
+    Other(MarvinDoc),
+}
+
+impl TypedDoc {
+    /// Parse a doc into the model for its `db`.
+    pub fn from_doc(doc: MarvinDoc) -> Result<Versioned<TypedDoc>, ApiError> {
+        let rev = doc.rev.clone();
+        let typed = match doc.db.as_deref() {
+            Some(Task::DB) => TypedDoc::Task(Box::new(parse(&doc)?)),
+            Some(ProjectOrCategory::DB) => TypedDoc::Category(parse(&doc)?),
+            Some(Habit::DB) => TypedDoc::Habit(parse(&doc)?),
+            Some(Goal::DB) => TypedDoc::Goal(parse(&doc)?),
+            Some(Label::DB) => TypedDoc::Label(parse(&doc)?),
+            Some(RecurringTask::DB) => TypedDoc::RecurringTask(parse(&doc)?),
+            Some(SavedItem::DB) => TypedDoc::SavedItem(parse(&doc)?),
+            _ => TypedDoc::Other(doc),
+        };
+        Ok(Versioned { rev, doc: typed })
+    }
+
+    /// The doc's `_id`.
+    pub fn id(&self) -> &str {
+        match self {
+            TypedDoc::Task(task) => &task.id,
+            TypedDoc::Category(category) => &category.id,
+            TypedDoc::Habit(habit) => &habit.id,
+            TypedDoc::Goal(goal) => &goal.id,
+            TypedDoc::Label(label) => &label.id,
+            TypedDoc::RecurringTask(recurring) => &recurring.id,
+            TypedDoc::SavedItem(saved) => &saved.id,
+            TypedDoc::Other(doc) => &doc.id,
+        }
+    }
+}
+
+/// Parse a doc into `T`, which has to be the model for the doc's `db`.
+pub fn parse<T: MarvinDb>(doc: &MarvinDoc) -> Result<T, ApiError> {
+    if doc.db.as_deref() != Some(T::DB) {
+        return Err(ApiError::DataError(format!(
+            "Doc {} is in {}, not {}",
+            doc.id,
+            doc.db.as_deref().unwrap_or("no db"),
+            T::DB
+        )));
+    }
+    serde_json::to_value(doc)
+        .and_then(serde_json::from_value)
+        .map_err(|err| {
+            ApiError::DataError(format!("Doc {} is not a valid {}: {}", doc.id, T::DB, err))
+        })
+}
+
+/// Keys the server manages; setting them would break the doc or its revisions.
+const RESERVED_KEYS: &[&str] = &["_id", "_rev", "db"];
+
+/// Check `setters` and add the bookkeeping Marvin's own clients send with every
+/// change: `fieldUpdates.<key>` for each field and `updatedAt`.
+pub fn prepare_setters(setters: Vec<DocSetter>, now_ms: i64) -> Result<Vec<DocSetter>, ApiError> {
+    let reserved = setters.iter().find(|setter| RESERVED_KEYS.contains(&setter.key.as_str()));
+    if let Some(setter) = reserved {
+        return Err(ApiError::DataError(format!("{} can't be updated", setter.key)));
+    }
+    let mut prepared = Vec::with_capacity(setters.len() * 2 + 1);
+    for setter in setters {
+        if setter.key != "updatedAt" && !setter.key.starts_with("fieldUpdates.") {
+            prepared.push(DocSetter::new(format!("fieldUpdates.{}", setter.key), now_ms));
+        }
+        prepared.push(setter);
+    }
+    if !prepared.iter().any(|setter| setter.key == "updatedAt") {
+        prepared.push(DocSetter::new("updatedAt", now_ms));
+    }
+    Ok(prepared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn doc(value: serde_json::Value) -> MarvinDoc {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn keys(setters: &[DocSetter]) -> Vec<&str> {
+        setters.iter().map(|setter| setter.key.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_a_doc_of_its_db() {
+        let label = doc(json!({"_id": "l1", "_rev": "1-a", "db": "Labels", "title": "deep work"}));
+        let label: Label = parse(&label).unwrap();
+        assert_eq!((label.id.as_str(), label.title.as_str()), ("l1", "deep work"));
+    }
+
+    #[test]
+    fn rejects_a_doc_of_another_db() {
+        let label = doc(json!({"_id": "l1", "db": "Labels", "title": "deep work"}));
+        assert!(matches!(parse::<Habit>(&label), Err(ApiError::DataError(_))));
+        let no_db = doc(json!({"_id": "l1", "title": "deep work"}));
+        assert!(matches!(parse::<Label>(&no_db), Err(ApiError::DataError(_))));
+    }
+
+    #[test]
+    fn rejects_a_doc_missing_fields() {
+        let label = doc(json!({"_id": "l1", "db": "Labels"}));
+        assert!(matches!(parse::<Label>(&label), Err(ApiError::DataError(_))));
+    }
+
+    #[test]
+    fn dispatches_on_db() {
+        let label = doc(json!({"_id": "l1", "_rev": "2-b", "db": "Labels", "title": "deep work"}));
+        let typed = TypedDoc::from_doc(label).unwrap();
+        assert_eq!(typed.rev.as_deref(), Some("2-b"));
+        assert!(matches!(typed.doc, TypedDoc::Label(_)));
+
+        let unknown = doc(json!({"_id": "x1", "db": "ProfileItems"}));
+        let typed = TypedDoc::from_doc(unknown).unwrap().doc;
+        assert!(matches!(typed, TypedDoc::Other(_)));
+        assert_eq!(typed.id(), "x1");
+    }
+
+    #[test]
+    fn refuses_reserved_keys() {
+        for key in RESERVED_KEYS {
+            let setters = vec![DocSetter::new(*key, "x")];
+            assert!(matches!(prepare_setters(setters, 0), Err(ApiError::DataError(_))));
+        }
+    }
+
+    #[test]
+    fn adds_field_updates_and_updated_at() {
+        let prepared = prepare_setters(vec![DocSetter::title("New")], 42).unwrap();
+        assert_eq!(keys(&prepared), ["fieldUpdates.title", "title", "updatedAt"]);
+        assert!(prepared.iter().filter(|setter| setter.key != "title").all(|setter| setter.val == 42));
+    }
+
+    #[test]
+    fn keeps_bookkeeping_the_caller_set() {
+        let setters = vec![
+            DocSetter::new("fieldUpdates.title", 7),
+            DocSetter::title("New"),
+            DocSetter::new("updatedAt", 7),
+        ];
+        let prepared = prepare_setters(setters, 42).unwrap();
+        assert_eq!(keys(&prepared), ["fieldUpdates.title", "fieldUpdates.title", "title", "updatedAt"]);
+        assert_eq!(prepared.last().unwrap().val, 7);
+    }
+}
@@ -6,12 +6,16 @@ pub enum ApiError {
     #[error("HTTP error: {0}")]
     HttpError(#[from] reqwest::Error),
 
-    #[error("Server returned status code {0}")]
-    StatusCodeError(StatusCode),
+    #[error("Server returned status code {status}: {body}")]
+    StatusCodeError { status: StatusCode, body: String },
 
     #[error("Invalid or unexpected data: {0}")]
     DataError(String),
 
+    /// The doc was changed by someone else since it was read
+    #[error("Doc {id} changed since it was read (read at {expected}, now at {found})")]
+    Conflict { id: String, expected: String, found: String },
+
     #[error("Other error: {0}")]
     Other(String),
 }
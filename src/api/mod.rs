@@ -2,3 +2,5 @@ pub mod client;
 pub mod requests;
 pub mod responses;
 pub mod error;
+pub mod docs;
+pub mod rate_limit;
@@ -0,0 +1,102 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+/// How many requests an endpoint may burst, and how quickly that allowance comes back.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Budget {
+    /// Requests that can be sent back to back
+    pub burst: u32,
+    /// Time for one more request to become available
+    pub interval_ms: u64,
+}
+
+/// Default budget for endpoints without one of their own
+const DEFAULT_BUDGET: Budget = Budget { burst: 5, interval_ms: 2000 };
+/// Endpoints that create documents, which Marvin limits more strictly
+const CREATE_BUDGET: Budget = Budget { burst: 1, interval_ms: 5000 };
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per Marvin endpoint. Requests wait for a token before they're sent,
+/// so bursts spread out instead of running into Marvin's 429s.
+#[derive(Debug)]
+pub struct RateLimiter {
+    default: Budget,
+    budgets: HashMap<String, Budget>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(default: Budget, budgets: HashMap<String, Budget>) -> Self {
+        Self {
+            default,
+            budgets,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Budgets from `MARVIN_RATE_LIMITS`, a JSON object of endpoint -> budget, e.g.
+    /// {"default": {"burst": 5, "interval_ms": 2000}, "addTask": {"burst": 1, "interval_ms": 5000}}
+    /// on top of the built-in ones.
+    pub fn from_env() -> Self {
+        let mut budgets: HashMap<String, Budget> = ["addTask", "addProject", "addEvent"]
+            .iter()
+            .map(|endpoint| (endpoint.to_string(), CREATE_BUDGET))
+            .collect();
+        if let Ok(val) = env::var("MARVIN_RATE_LIMITS") {
+            match serde_json::from_str::<HashMap<String, Budget>>(&val) {
+                Ok(overrides) => budgets.extend(overrides),
+                Err(err) => eprintln!("MARVIN_RATE_LIMITS is not valid JSON: {}", err),
+            }
+        }
+        let default = budgets.remove("default").unwrap_or(DEFAULT_BUDGET);
+        Self::new(default, budgets)
+    }
+
+    fn budget(&self, endpoint: &str) -> Budget {
+        self.budgets.get(endpoint).copied().unwrap_or(self.default)
+    }
+
+    /// Take a token for `endpoint`, or how long until the next one is available.
+    fn try_acquire(&self, endpoint: &str) -> Result<(), Duration> {
+        let budget = self.budget(endpoint);
+        let burst = budget.burst.max(1) as f64;
+        let interval = Duration::from_millis(budget.interval_ms.max(1));
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(endpoint.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: Instant::now(),
+        });
+        let refill = bucket.updated.elapsed().as_secs_f64() / interval.as_secs_f64();
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = Instant::now();
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(interval.mul_f64(1.0 - bucket.tokens))
+    }
+
+    /// Wait until a request to `endpoint` fits into its budget.
+    pub async fn acquire(&self, endpoint: &str) {
+        while let Err(wait) = self.try_acquire(endpoint) {
+            tracing::debug!(endpoint, ?wait, "waiting for Marvin rate limit");
+            sleep(wait).await;
+        }
+    }
+}
+
+/// One limiter for the whole process; every MarvinClient shares it unless it's given
+/// its own, since clients are created per webhook.
+pub static MARVIN_RATE_LIMITER: LazyLock<Arc<RateLimiter>> =
+    LazyLock::new(|| Arc::new(RateLimiter::from_env()));
@@ -10,7 +10,7 @@ pub struct TestRequest {
 }
 
 /// POST body to create a new Task.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateTaskRequest {
     #[serde(default)]
@@ -198,6 +198,16 @@ pub struct DocSetter {
     pub val: Value,
 }
 
+impl DocSetter {
+    pub fn new(key: impl Into<String>, val: impl Into<Value>) -> Self {
+        Self { key: key.into(), val: val.into() }
+    }
+
+    pub fn title(title: &str) -> Self {
+        Self::new("title", title)
+    }
+}
+
 /// POST body for creating an entirely new doc
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
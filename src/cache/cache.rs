@@ -1,89 +1,483 @@
-use std::{collections::HashMap, fmt::Debug, hash::Hash, sync::{Arc, LazyLock, Mutex}, time::{Duration, Instant}};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    fmt::Debug,
+    hash::Hash,
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// Default lifetime of Marvin entries; labels change without telling us
+const MARVIN_TTL: Duration = Duration::from_secs(60 * 5);
+/// Default lifetime of Toggl entries
+const TOGGL_TTL: Duration = Duration::from_secs(60 * 5);
+/// Default lifetime of Toggl entries when a Toggl webhook subscription
+/// (`TOGGL_WEBHOOK_SECRET`) keeps them up to date
+const TOGGL_WEBHOOK_TTL: Duration = Duration::from_secs(60 * 30);
+/// Default number of entries per cache
+const DEFAULT_CAPACITY: usize = 1000;
+
+/// Toggl entries only live longer when Toggl tells us about changes.
+fn toggl_ttl() -> Duration {
+    if env::var("TOGGL_WEBHOOK_SECRET").is_ok() {
+        TOGGL_WEBHOOK_TTL
+    } else {
+        TOGGL_TTL
+    }
+}
 
 pub struct CacheItem<T> {
     pub time: Instant,
-    pub value: T
+    /// Wall-clock version of `time`, kept in snapshots
+    pub cached_at: DateTime<Utc>,
+    /// Value of the cache's use counter when this item was last read or written
+    pub last_used: u64,
+    /// Loaded from a snapshot and not refreshed since. Served even after the TTL
+    /// until it's revalidated or dropped.
+    pub restored: bool,
+    pub value: T,
 }
 
-type Cache<K, V> where K: Eq, K: Hash = Arc<Mutex<HashMap<K, CacheItem<V>>>>;
+/// One cache entry as written to disk.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotEntry<K, V> {
+    pub key: K,
+    pub value: V,
+    pub cached_at: DateTime<Utc>,
+}
 
-// Title, parent id
-pub static MARVIN_PROJECT_CACHE: LazyLock<Cache<String, (String, String)>> = LazyLock::new(|| {
-    Arc::new(Mutex::new(HashMap::new()))
-});
+/// Counters for a cache since startup.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Lookups that found an entry older than the TTL
+    pub expired: u64,
+    /// Entries dropped to stay within capacity
+    pub evicted: u64,
+}
 
-pub static MARVIN_LABEL_CACHE: LazyLock<Cache<String, String>> = LazyLock::new(|| {
-    Arc::new(Mutex::new(HashMap::new()))
-});
+/// Keys that can be written in admin URLs: names as they are, (ID, name) pairs as "ID:name".
+pub trait CacheKey: Sized {
+    fn to_key_string(&self) -> String;
+    fn parse_key(key: &str) -> Option<Self>;
+}
 
-pub static TOGGL_CLIENT_CACHE: LazyLock<Cache<String, i64>> = LazyLock::new(|| {
-    Arc::new(Mutex::new(HashMap::new()))
-});
+impl CacheKey for String {
+    fn to_key_string(&self) -> String {
+        self.clone()
+    }
 
-pub static TOGGL_PROJECT_CACHE: LazyLock<Cache<(i64, String), i64>> = LazyLock::new(|| {
-    Arc::new(Mutex::new(HashMap::new()))
-});
+    fn parse_key(key: &str) -> Option<Self> {
+        Some(key.to_string())
+    }
+}
 
-pub static TOGGL_TASK_CACHE: LazyLock<Cache<(i64, String), i64>> = LazyLock::new(|| {
-    Arc::new(Mutex::new(HashMap::new()))
-});
+impl CacheKey for (i64, String) {
+    fn to_key_string(&self) -> String {
+        format!("{}:{}", self.0, self.1)
+    }
 
-pub static TOGGL_TAG_CACHE: LazyLock<Cache<String, i64>> = LazyLock::new(|| {
-    Arc::new(Mutex::new(HashMap::new()))
-});
+    fn parse_key(key: &str) -> Option<Self> {
+        let (id, name) = key.split_once(':')?;
+        Some((id.parse().ok()?, name.to_string()))
+    }
+}
 
-/// Log the current state of all Toggl caches
-pub fn log_toggl_cache_state() {
-    let clients = TOGGL_CLIENT_CACHE.lock().unwrap();
-    let projects = TOGGL_PROJECT_CACHE.lock().unwrap();
-    let tasks = TOGGL_TASK_CACHE.lock().unwrap();
-    let tags = TOGGL_TAG_CACHE.lock().unwrap();
+/// One entry in a `CacheReport`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheEntryReport {
+    pub key: String,
+    pub value: serde_json::Value,
+    pub age_secs: u64,
+    /// Older than the TTL, or restored from a snapshot and not revalidated yet
+    pub stale: bool,
+}
 
-    println!("=== TOGGL CACHE STATE ===");
-    println!("Clients ({} entries):", clients.len());
-    for (name, item) in clients.iter() {
-        println!("  '{}' -> {}", name, item.value);
+/// Contents and counters of a cache, for the admin endpoints.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheReport {
+    pub name: &'static str,
+    pub ttl_secs: u64,
+    pub capacity: usize,
+    pub len: usize,
+    pub stats: CacheStats,
+    pub entries: Vec<CacheEntryReport>,
+}
+
+/// Cache items, with their keys ordered by last use so the least recently used
+/// one is found without scanning every item.
+struct LruItems<K, V> {
+    items: HashMap<K, CacheItem<V>>,
+    /// `last_used` of each item -> its key. Use counter values are unique.
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Eq + Hash + Clone, V> LruItems<K, V> {
+    fn new() -> Self {
+        LruItems { items: HashMap::new(), order: BTreeMap::new() }
     }
-    println!("Projects ({} entries):", projects.len());
-    for ((client_id, name), item) in projects.iter() {
-        println!("  ({}, '{}') -> {}", client_id, name, item.value);
+
+    fn len(&self) -> usize {
+        self.items.len()
     }
-    println!("Tasks ({} entries):", tasks.len());
-    for ((project_id, name), item) in tasks.iter() {
-        println!("  ({}, '{}') -> {}", project_id, name, item.value);
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.items.contains_key(key)
     }
-    println!("Tags ({} entries):", tags.len());
-    for (name, item) in tags.iter() {
-        println!("  '{}' -> {}", name, item.value);
+
+    fn get(&self, key: &K) -> Option<&CacheItem<V>> {
+        self.items.get(key)
     }
-    println!("=========================");
+
+    fn iter(&self) -> impl Iterator<Item = (&K, &CacheItem<V>)> {
+        self.items.iter()
+    }
+
+    fn insert(&mut self, key: K, item: CacheItem<V>) {
+        self.order.insert(item.last_used, key.clone());
+        if let Some(old) = self.items.insert(key, item) {
+            self.order.remove(&old.last_used);
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<CacheItem<V>> {
+        let item = self.items.remove(key)?;
+        self.order.remove(&item.last_used);
+        Some(item)
+    }
+
+    /// Mark `key` as used at `tick`.
+    fn touch(&mut self, key: &K, tick: u64) {
+        if let Some(item) = self.items.get_mut(key) {
+            self.order.remove(&item.last_used);
+            item.last_used = tick;
+            self.order.insert(tick, key.clone());
+        }
+    }
+
+    fn retain<F: FnMut(&K, &CacheItem<V>) -> bool>(&mut self, mut keep: F) {
+        let order = &mut self.order;
+        self.items.retain(|key, item| {
+            let kept = keep(key, item);
+            if !kept {
+                order.remove(&item.last_used);
+            }
+            kept
+        });
+    }
+
+    fn clear(&mut self) {
+        self.items.clear();
+        self.order.clear();
+    }
+
+    /// Remove the least recently used item.
+    fn pop_oldest(&mut self) -> Option<K> {
+        let (_, key) = self.order.pop_first()?;
+        self.items.remove(&key);
+        Some(key)
+    }
+}
+
+/// An in-memory key/value cache with a TTL and a least-recently-used size bound.
+/// Each cache can be configured with `CACHE_<NAME>_TTL_SECS` and `CACHE_<NAME>_CAPACITY`.
+pub struct Cache<K, V> {
+    name: &'static str,
+    ttl: Duration,
+    capacity: usize,
+    items: Mutex<LruItems<K, V>>,
+    uses: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    evicted: AtomicU64,
 }
 
-pub fn cache_get<K, V>(cache: Cache<K, V>, key: &K) -> Option<V> where K: Eq, K: Hash + Debug, V: Clone + Debug {
-    let now = Instant::now();
-    let map = cache.lock().unwrap();
-    match map.get(&key) {
-        Some(item) => {
-            if now - item.time >= Duration::from_secs(60 * 5) {
-                println!("[CACHE] GET {:?} -> EXPIRED", key);
-                return None
+impl<K, V> Cache<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Debug,
+{
+    pub fn new(name: &'static str, ttl: Duration, capacity: usize) -> Self {
+        let setting = |suffix: &str| {
+            let var = format!("CACHE_{}_{}", name.to_uppercase(), suffix);
+            env::var(&var).ok().and_then(|val| match val.parse::<u64>() {
+                Ok(val) => Some(val),
+                Err(_) => {
+                    eprintln!("{} is not a number, using the default", var);
+                    None
+                }
+            })
+        };
+        Cache {
+            name,
+            ttl: setting("TTL_SECS").map(Duration::from_secs).unwrap_or(ttl),
+            capacity: setting("CAPACITY").map(|val| val as usize).unwrap_or(capacity).max(1),
+            items: Mutex::new(LruItems::new()),
+            uses: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn tick(&self) -> u64 {
+        self.uses.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub async fn get(&self, key: &K) -> Option<V> {
+        let mut items = self.items.lock().await;
+        match items.get(key) {
+            Some(item) if item.restored => {
+                tracing::debug!(cache = self.name, ?key, "stale hit");
+                let value = item.value.clone();
+                items.touch(key, self.tick());
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value)
+            }
+            Some(item) if item.time.elapsed() >= self.ttl => {
+                tracing::debug!(cache = self.name, ?key, "expired");
+                items.remove(key);
+                self.expired.fetch_add(1, Ordering::Relaxed);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Some(item) => {
+                tracing::debug!(cache = self.name, ?key, "hit");
+                let value = item.value.clone();
+                items.touch(key, self.tick());
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value)
+            }
+            None => {
+                tracing::debug!(cache = self.name, ?key, "miss");
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
             }
-            println!("[CACHE] GET {:?} -> HIT: {:?}", key, item.value);
-            Some(item.value.clone())
         }
-        None => {
-            println!("[CACHE] GET {:?} -> MISS", key);
-            None
+    }
+
+    pub async fn put(&self, key: K, value: V) {
+        tracing::debug!(cache = self.name, ?key, ?value, "put");
+        let mut items = self.items.lock().await;
+        let item = CacheItem {
+            time: Instant::now(),
+            cached_at: Utc::now(),
+            last_used: self.tick(),
+            restored: false,
+            value,
+        };
+        items.insert(key, item);
+        self.evict(&mut items);
+    }
+
+    fn evict(&self, items: &mut LruItems<K, V>) {
+        // Evict the least recently used entries beyond capacity
+        while items.len() > self.capacity {
+            match items.pop_oldest() {
+                Some(_) => {
+                    self.evicted.fetch_add(1, Ordering::Relaxed);
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub async fn remove(&self, key: &K) -> Option<V> {
+        self.items.lock().await.remove(key).map(|item| item.value)
+    }
+
+    /// Drop every entry. Returns how many there were.
+    pub async fn clear(&self) -> usize {
+        let mut items = self.items.lock().await;
+        let len = items.len();
+        items.clear();
+        len
+    }
+
+    /// Drop every entry for which `predicate` returns true.
+    pub async fn remove_where<F>(&self, predicate: F)
+    where
+        F: Fn(&K, &V) -> bool,
+    {
+        let mut items = self.items.lock().await;
+        items.retain(|key, item| {
+            let remove = predicate(key, &item.value);
+            if remove {
+                println!("[CACHE] {}: remove {:?} -> {:?}", self.name, key, item.value);
+            }
+            !remove
+        });
+    }
+
+    /// Keys of entries loaded from a snapshot that haven't been refreshed yet.
+    pub async fn restored_keys(&self) -> Vec<K> {
+        let items = self.items.lock().await;
+        items
+            .iter()
+            .filter(|(_, item)| item.restored)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Drop entries loaded from a snapshot that weren't refreshed; revalidation
+    /// didn't find them anymore. Returns how many were dropped.
+    pub async fn drop_restored(&self) -> usize {
+        let mut items = self.items.lock().await;
+        let before = items.len();
+        items.retain(|_, item| !item.restored);
+        before - items.len()
+    }
+
+    /// All entries that haven't expired yet.
+    pub async fn entries(&self) -> Vec<(K, V)> {
+        let items = self.items.lock().await;
+        items
+            .iter()
+            .filter(|(_, item)| item.restored || item.time.elapsed() < self.ttl)
+            .map(|(key, item)| (key.clone(), item.value.clone()))
+            .collect()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
         }
     }
 }
 
-pub fn cache_put<K, V>(cache: Cache<K, V>, key: K, value: V) where K: Eq, K: Hash + Debug, V: Clone + Debug {
-    println!("[CACHE] PUT {:?} -> {:?}", key, value);
-    let now = Instant::now();
-    let mut map = cache.lock().unwrap();
-    map.insert(key, CacheItem {
-        time: now,
-        value
-    });
+impl<K, V> Cache<K, V>
+where
+    K: Eq + Hash + Clone + Debug + Serialize + DeserializeOwned,
+    V: Clone + Debug + Serialize + DeserializeOwned,
+{
+    /// Every entry (expired or not) with its age, and the cache's counters.
+    pub async fn report(&self) -> CacheReport
+    where
+        K: CacheKey,
+    {
+        let items = self.items.lock().await;
+        let mut entries: Vec<CacheEntryReport> = items
+            .iter()
+            .map(|(key, item)| CacheEntryReport {
+                key: key.to_key_string(),
+                value: serde_json::to_value(&item.value).unwrap_or_default(),
+                age_secs: item.time.elapsed().as_secs(),
+                stale: item.restored || item.time.elapsed() >= self.ttl,
+            })
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        CacheReport {
+            name: self.name,
+            ttl_secs: self.ttl.as_secs(),
+            capacity: self.capacity,
+            len: items.len(),
+            stats: self.stats(),
+            entries,
+        }
+    }
+
+    /// Current entries, including stale restored ones, for writing to disk.
+    pub async fn snapshot(&self) -> Vec<SnapshotEntry<K, V>> {
+        let items = self.items.lock().await;
+        items
+            .iter()
+            .filter(|(_, item)| item.restored || item.time.elapsed() < self.ttl)
+            .map(|(key, item)| SnapshotEntry {
+                key: key.clone(),
+                value: item.value.clone(),
+                cached_at: item.cached_at,
+            })
+            .collect()
+    }
+
+    /// Load entries from a snapshot. They're served (stale or not) until they're
+    /// refreshed with `put` or dropped with `drop_restored`.
+    /// Entries already in the cache win over the snapshot.
+    pub async fn restore(&self, entries: Vec<SnapshotEntry<K, V>>) -> usize {
+        let mut items = self.items.lock().await;
+        let mut restored = 0;
+        for entry in entries {
+            if items.contains_key(&entry.key) {
+                continue;
+            }
+            let age = (Utc::now() - entry.cached_at).to_std().unwrap_or_default();
+            let item = CacheItem {
+                time: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+                cached_at: entry.cached_at,
+                last_used: self.tick(),
+                restored: true,
+                value: entry.value,
+            };
+            items.insert(entry.key, item);
+            restored += 1;
+        }
+        self.evict(&mut items);
+        restored
+    }
+}
+
+pub static MARVIN_LABEL_CACHE: LazyLock<Cache<String, String>> =
+    LazyLock::new(|| Cache::new("marvin_labels", MARVIN_TTL, DEFAULT_CAPACITY));
+
+pub static TOGGL_CLIENT_CACHE: LazyLock<Cache<String, i64>> =
+    LazyLock::new(|| Cache::new("toggl_clients", toggl_ttl(), DEFAULT_CAPACITY));
+
+pub static TOGGL_PROJECT_CACHE: LazyLock<Cache<(i64, String), i64>> =
+    LazyLock::new(|| Cache::new("toggl_projects", toggl_ttl(), DEFAULT_CAPACITY));
+
+pub static TOGGL_TASK_CACHE: LazyLock<Cache<(i64, String), i64>> =
+    LazyLock::new(|| Cache::new("toggl_tasks", toggl_ttl(), DEFAULT_CAPACITY));
+
+pub static TOGGL_TAG_CACHE: LazyLock<Cache<String, i64>> =
+    LazyLock::new(|| Cache::new("toggl_tags", toggl_ttl(), DEFAULT_CAPACITY));
+
+/// Log the current state of all Toggl caches
+pub async fn log_toggl_cache_state() {
+    let clients = TOGGL_CLIENT_CACHE.entries().await;
+    let projects = TOGGL_PROJECT_CACHE.entries().await;
+    let tasks = TOGGL_TASK_CACHE.entries().await;
+    let tags = TOGGL_TAG_CACHE.entries().await;
+
+    println!("=== TOGGL CACHE STATE ===");
+    for (name, stats) in [
+        (TOGGL_CLIENT_CACHE.name(), TOGGL_CLIENT_CACHE.stats()),
+        (TOGGL_PROJECT_CACHE.name(), TOGGL_PROJECT_CACHE.stats()),
+        (TOGGL_TASK_CACHE.name(), TOGGL_TASK_CACHE.stats()),
+        (TOGGL_TAG_CACHE.name(), TOGGL_TAG_CACHE.stats()),
+    ] {
+        println!("{}: {:?}", name, stats);
+    }
+    println!("Clients ({} entries):", clients.len());
+    for (name, id) in clients.iter() {
+        println!("  '{}' -> {}", name, id);
+    }
+    println!("Projects ({} entries):", projects.len());
+    for ((client_id, name), id) in projects.iter() {
+        println!("  ({}, '{}') -> {}", client_id, name, id);
+    }
+    println!("Tasks ({} entries):", tasks.len());
+    for ((project_id, name), id) in tasks.iter() {
+        println!("  ({}, '{}') -> {}", project_id, name, id);
+    }
+    println!("Tags ({} entries):", tags.len());
+    for (name, id) in tags.iter() {
+        println!("  '{}' -> {}", name, id);
+    }
+    println!("=========================");
 }
@@ -0,0 +1,352 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{LazyLock, RwLock},
+};
+
+use crate::{
+    cache::single_flight::IN_FLIGHT, jobs::error::WebhookError,
+    models::tasks::ProjectOrCategory, routes::marvin_webhooks::marvin_client_from_env,
+    store::json,
+};
+
+/// Name of the tree in the admin endpoints
+pub const CATEGORY_TREE_NAME: &str = "marvin_categories";
+/// File (inside the data directory) the tree is snapshotted to.
+const CATEGORIES_FILE: &str = "cache_marvin_categories.json";
+
+/// Parent IDs that aren't categories themselves.
+pub fn is_top_level(id: &str) -> bool {
+    id == "root" || id == "unassigned"
+}
+
+/// All Marvin categories and projects, indexed by ID and by parent.
+#[derive(Debug, Default)]
+pub struct CategoryTree {
+    items: HashMap<String, ProjectOrCategory>,
+    children: HashMap<String, BTreeSet<String>>,
+    /// When the whole tree was last fetched from Marvin; None until it was
+    loaded_at: Option<DateTime<Utc>>,
+    /// Loaded from a snapshot and not fetched from Marvin since
+    restored: bool,
+}
+
+impl CategoryTree {
+    fn from_items(items: Vec<ProjectOrCategory>) -> Self {
+        let mut tree = CategoryTree::default();
+        for item in items {
+            tree.insert(item);
+        }
+        tree
+    }
+
+    fn insert(&mut self, item: ProjectOrCategory) {
+        if let Some(old) = self.items.get(&item.id)
+            && old.parent_id != item.parent_id
+            && let Some(siblings) = self.children.get_mut(&old.parent_id)
+        {
+            siblings.remove(&item.id);
+        }
+        self.children.entry(item.parent_id.clone()).or_default().insert(item.id.clone());
+        self.items.insert(item.id.clone(), item);
+    }
+
+    fn remove(&mut self, id: &str) -> Option<ProjectOrCategory> {
+        let item = self.items.remove(id)?;
+        if let Some(siblings) = self.children.get_mut(&item.parent_id) {
+            siblings.remove(id);
+        }
+        Some(item)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ProjectOrCategory> {
+        self.items.get(id)
+    }
+
+    /// Direct children of `id` ("root" for the top level).
+    pub fn children(&self, id: &str) -> Vec<&ProjectOrCategory> {
+        self.children
+            .get(id)
+            .map(|ids| ids.iter().filter_map(|id| self.items.get(id)).collect())
+            .unwrap_or_default()
+    }
+
+    /// `id` and everything above it, nearest first. None if a category on the way
+    /// up isn't in the tree.
+    pub fn ancestors(&self, id: &str) -> Option<Vec<&ProjectOrCategory>> {
+        let mut ancestors = vec![];
+        let mut seen = HashSet::new();
+        let mut id = id;
+        while !is_top_level(id) {
+            // A parent cycle would loop forever
+            if !seen.insert(id) {
+                break;
+            }
+            let item = self.items.get(id)?;
+            ancestors.push(item);
+            id = &item.parent_id;
+        }
+        Some(ancestors)
+    }
+
+    /// Titles from the top level down to `id`, e.g. ["Work", "Marvinhooks"].
+    pub fn path(&self, id: &str) -> Option<Vec<String>> {
+        let mut path: Vec<String> =
+            self.ancestors(id)?.iter().map(|item| item.title.clone()).collect();
+        path.reverse();
+        Some(path)
+    }
+
+    /// The category or project at `path` (titles from the top level down).
+    pub fn find_by_path(&self, path: &[&str]) -> Option<&ProjectOrCategory> {
+        self.find_by_path_matching(path, |title, wanted| title == wanted)
+    }
+
+    /// Like `find_by_path`, with `matches(title, wanted)` deciding whether titles match.
+    pub fn find_by_path_matching<S, F>(&self, path: &[S], matches: F) -> Option<&ProjectOrCategory>
+    where
+        S: AsRef<str>,
+        F: Fn(&str, &str) -> bool,
+    {
+        let mut parent = "root".to_string();
+        let mut found = None;
+        for title in path {
+            let item = self
+                .children(&parent)
+                .into_iter()
+                .find(|item| matches(&item.title, title.as_ref()))?;
+            parent = item.id.clone();
+            found = Some(item);
+        }
+        found
+    }
+}
+
+/// Tree snapshot on disk.
+#[derive(Serialize, Deserialize)]
+struct CategorySnapshot {
+    loaded_at: DateTime<Utc>,
+    items: Vec<ProjectOrCategory>,
+}
+
+pub static CATEGORY_TREE: LazyLock<RwLock<CategoryTree>> =
+    LazyLock::new(|| RwLock::new(CategoryTree::default()));
+
+/// Fetch every category and project from Marvin and replace the tree.
+pub async fn reload() -> Result<(), WebhookError> {
+    let marvin_client = marvin_client_from_env()?;
+    let items = marvin_client.get_categories().await?;
+    println!("[CATEGORIES] Loaded {} categories and projects", items.len());
+    let mut tree = CategoryTree::from_items(items);
+    tree.loaded_at = Some(Utc::now());
+    *CATEGORY_TREE.write().unwrap() = tree;
+    Ok(())
+}
+
+/// `reload`, sharing one request between concurrent callers.
+async fn reload_shared() -> Result<(), WebhookError> {
+    IN_FLIGHT
+        .run("marvin_categories", || async { reload().await.map(|()| true) })
+        .await
+}
+
+/// Read `id` and the parents above it that the tree is missing from Marvin, one doc
+/// at a time, and add them to the tree.
+async fn fetch_missing(id: &str) -> Result<(), WebhookError> {
+    let marvin_client = marvin_client_from_env()?;
+    let mut id = id.to_string();
+    while !is_top_level(&id) && CATEGORY_TREE.read().unwrap().get(&id).is_none() {
+        let item = marvin_client.read_doc_as::<ProjectOrCategory>(&id).await?.doc;
+        id = item.parent_id.clone();
+        upsert(item);
+    }
+    Ok(())
+}
+
+/// `id` and everything above it, nearest first. Loads the tree on first use. If `id`
+/// or one of its parents is missing (e.g. a project created before its webhook
+/// arrived), reads the missing docs, and reloads the tree once if that fails.
+/// Concurrent loads share one request.
+pub async fn ancestors(id: &str) -> Result<Vec<ProjectOrCategory>, WebhookError> {
+    let lookup = || {
+        let tree = CATEGORY_TREE.read().unwrap();
+        tree.loaded_at?;
+        tree.ancestors(id).map(|items| items.into_iter().cloned().collect())
+    };
+    if let Some(ancestors) = lookup() {
+        return Ok(ancestors);
+    }
+    if CATEGORY_TREE.read().unwrap().loaded_at.is_some() {
+        match fetch_missing(id).await {
+            Ok(()) => {
+                if let Some(ancestors) = lookup() {
+                    return Ok(ancestors);
+                }
+            }
+            Err(err) => println!("[CATEGORIES] Reading {} failed: {}", id, err.chain().join(": ")),
+        }
+    }
+    reload_shared().await?;
+    lookup().ok_or_else(|| {
+        WebhookError::DataError(format!("Marvin category {} or one of its parents is unknown", id))
+    })
+}
+
+/// Whether a Marvin doc from a webhook is a category or project.
+pub fn is_category_doc(doc: &Value) -> bool {
+    doc.get("db").and_then(Value::as_str) == Some("Categories")
+        || matches!(doc.get("type").and_then(Value::as_str), Some("category" | "project"))
+}
+
+/// A category or project with where it is in the tree.
+#[derive(Debug, Clone, Serialize)]
+pub struct CategoryInfo {
+    pub item: ProjectOrCategory,
+    /// Titles from the top level down to the item
+    pub path: Vec<String>,
+    pub children: Vec<ProjectOrCategory>,
+}
+
+/// Look up a category or project by ID, or by its path of titles separated by '/'.
+pub async fn lookup(
+    id: Option<&str>,
+    path: Option<&str>,
+) -> Result<Option<CategoryInfo>, WebhookError> {
+    if CATEGORY_TREE.read().unwrap().loaded_at.is_none() {
+        reload_shared().await?;
+    }
+    let tree = CATEGORY_TREE.read().unwrap();
+    let item = match (id, path) {
+        (Some(id), _) => tree.get(id),
+        (None, Some(path)) => {
+            let titles: Vec<&str> = path.split('/').filter(|title| !title.is_empty()).collect();
+            tree.find_by_path(&titles)
+        }
+        (None, None) => None,
+    };
+    Ok(item.map(|item| CategoryInfo {
+        item: item.clone(),
+        path: tree.path(&item.id).unwrap_or_default(),
+        children: tree.children(&item.id).into_iter().cloned().collect(),
+    }))
+}
+
+/// ID of the category or project at `path` (titles from the top level down), with
+/// `matches(title, wanted)` comparing titles. Loads the tree on first use.
+pub async fn find_path<S, F>(path: &[S], matches: F) -> Result<Option<String>, WebhookError>
+where
+    S: AsRef<str>,
+    F: Fn(&str, &str) -> bool,
+{
+    if CATEGORY_TREE.read().unwrap().loaded_at.is_none() {
+        reload_shared().await?;
+    }
+    let tree = CATEGORY_TREE.read().unwrap();
+    Ok(tree.find_by_path_matching(path, matches).map(|item| item.id.clone()))
+}
+
+/// Add or update a category or project after a Marvin webhook.
+pub fn upsert(item: ProjectOrCategory) {
+    println!("[CATEGORIES] '{}' ({}) changed", item.title, item.id);
+    CATEGORY_TREE.write().unwrap().insert(item);
+}
+
+/// Forget a deleted category or project.
+pub fn remove(id: &str) -> Option<ProjectOrCategory> {
+    let item = CATEGORY_TREE.write().unwrap().remove(id)?;
+    println!("[CATEGORIES] '{}' ({}) deleted", item.title, item.id);
+    Some(item)
+}
+
+/// Write the tree to the data directory.
+pub fn save() {
+    let tree = CATEGORY_TREE.read().unwrap();
+    let loaded_at = match tree.loaded_at {
+        Some(loaded_at) => loaded_at,
+        None => return,
+    };
+    let snapshot = CategorySnapshot {
+        loaded_at,
+        items: tree.items.values().cloned().collect(),
+    };
+    if let Err(err) = json::save_json(CATEGORIES_FILE, &snapshot) {
+        eprintln!("[CATEGORIES] Could not save snapshot: {}", err);
+    }
+}
+
+/// Load the tree from its last snapshot unless it's older than `max_age_secs`.
+/// It's served as it is until `revalidate` fetches it again.
+pub fn restore(max_age_secs: i64) {
+    let snapshot: CategorySnapshot = match json::load_json(CATEGORIES_FILE) {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+    if (Utc::now() - snapshot.loaded_at).num_seconds() > max_age_secs {
+        return;
+    }
+    let mut tree = CategoryTree::from_items(snapshot.items);
+    tree.loaded_at = Some(snapshot.loaded_at);
+    tree.restored = true;
+    println!("[CATEGORIES] Restored {} categories and projects", tree.items.len());
+    *CATEGORY_TREE.write().unwrap() = tree;
+}
+
+/// Fetch the tree again if it was restored from a snapshot.
+pub async fn revalidate() -> Result<(), WebhookError> {
+    if !CATEGORY_TREE.read().unwrap().restored {
+        return Ok(());
+    }
+    reload_shared().await
+}
+
+/// One category or project in a `CategoryTreeReport`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CategoryEntry {
+    pub id: String,
+    pub title: String,
+    pub r#type: String,
+    pub parent_id: String,
+}
+
+/// Contents of the category tree, for the admin endpoints.
+#[derive(Debug, Clone, Serialize)]
+pub struct CategoryTreeReport {
+    pub name: &'static str,
+    pub len: usize,
+    pub loaded_at: Option<DateTime<Utc>>,
+    /// Restored from a snapshot and not revalidated yet
+    pub stale: bool,
+    pub entries: Vec<CategoryEntry>,
+}
+
+pub fn report() -> CategoryTreeReport {
+    let tree = CATEGORY_TREE.read().unwrap();
+    let mut entries: Vec<CategoryEntry> = tree
+        .items
+        .values()
+        .map(|item| CategoryEntry {
+            id: item.id.clone(),
+            title: item.title.clone(),
+            r#type: item.r#type.clone(),
+            parent_id: item.parent_id.clone(),
+        })
+        .collect();
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    CategoryTreeReport {
+        name: CATEGORY_TREE_NAME,
+        len: tree.items.len(),
+        loaded_at: tree.loaded_at,
+        stale: tree.restored,
+        entries,
+    }
+}
+
+/// Empty the tree; the next lookup loads it again. Returns how many items it had.
+pub fn clear() -> usize {
+    let mut tree = CATEGORY_TREE.write().unwrap();
+    let len = tree.items.len();
+    *tree = CategoryTree::default();
+    len
+}
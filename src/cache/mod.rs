@@ -1 +1,5 @@
 pub mod cache;
+pub mod categories;
+pub mod snapshot;
+pub mod single_flight;
+pub mod warmup;
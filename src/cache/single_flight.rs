@@ -0,0 +1,73 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// One upstream request that concurrent cache misses wait on.
+#[derive(Default)]
+struct Flight {
+    lock: Arc<AsyncMutex<()>>,
+    /// Number of times the request was sent and completed successfully
+    completed: AtomicU64,
+}
+
+/// Coalesces concurrent cache misses: while a request for a key is in flight, other
+/// callers for the same key wait for it and then read its results from the cache
+/// instead of sending the same request again.
+#[derive(Default)]
+pub struct SingleFlight {
+    flights: Mutex<HashMap<String, Arc<Flight>>>,
+}
+
+/// Drops the flight from the map once nobody is using it, so keys for single names
+/// don't pile up.
+struct Landing<'a> {
+    flights: &'a Mutex<HashMap<String, Arc<Flight>>>,
+    key: &'a str,
+    flight: Arc<Flight>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        let mut flights = self.flights.lock().unwrap();
+        // Only the map and this landing hold it; new callers need the map lock to join
+        if Arc::strong_count(&self.flight) == 2 {
+            flights.remove(self.key);
+        }
+    }
+}
+
+impl SingleFlight {
+    /// Run `fetch`, which should put its results into a cache, unless a fetch for
+    /// `key` completed while we waited for it. `fetch` returns whether it sent the
+    /// request; one that found what it needed already cached doesn't count, since
+    /// callers sharing a key may be after different entries. Failed fetches aren't
+    /// shared; the next caller in line tries again.
+    pub async fn run<F, Fut, E>(&self, key: &str, fetch: F) -> Result<(), E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<bool, E>>,
+    {
+        let flight = self.flights.lock().unwrap().entry(key.to_string()).or_default().clone();
+        let landing = Landing { flights: &self.flights, key, flight };
+        let flight = &landing.flight;
+        let seen = flight.completed.load(Ordering::SeqCst);
+        let _guard = flight.lock.clone().lock_owned().await;
+        if flight.completed.load(Ordering::SeqCst) != seen {
+            tracing::debug!(key, "joined in-flight request");
+            return Ok(());
+        }
+        if fetch().await? {
+            flight.completed.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+/// Upstream lookups behind the Marvin and Toggl caches.
+pub static IN_FLIGHT: LazyLock<SingleFlight> = LazyLock::new(SingleFlight::default);
@@ -0,0 +1,122 @@
+use chrono::{Duration as ChronoDuration, Utc};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{env, fmt::Debug, hash::Hash, time::Duration};
+use tokio::time::sleep;
+
+use crate::{
+    cache::{
+        cache::{Cache, MARVIN_LABEL_CACHE, SnapshotEntry},
+        categories,
+    },
+    jobs::error::WebhookError,
+    routes::marvin_webhooks::marvin_client_from_env,
+    store::json,
+};
+
+/// Default time between snapshots
+const DEFAULT_INTERVAL_SECS: u64 = 60 * 5;
+/// Default age after which snapshot entries aren't worth restoring
+const DEFAULT_MAX_AGE_SECS: i64 = 60 * 60 * 24 * 7;
+/// First wait before retrying a failed revalidation; doubled after every failure
+const REVALIDATE_RETRY_SECS: u64 = 5;
+/// Longest wait between revalidation attempts
+const REVALIDATE_MAX_RETRY_SECS: u64 = 60 * 5;
+
+fn snapshot_file(name: &str) -> String {
+    format!("cache_{}.json", name)
+}
+
+async fn save<K, V>(cache: &Cache<K, V>)
+where
+    K: Eq + Hash + Clone + Debug + Serialize + DeserializeOwned,
+    V: Clone + Debug + Serialize + DeserializeOwned,
+{
+    let entries = cache.snapshot().await;
+    if let Err(err) = json::save_json(&snapshot_file(cache.name()), &entries) {
+        eprintln!("[CACHE] Could not save snapshot of {}: {}", cache.name(), err);
+    }
+}
+
+/// Age after which snapshot entries aren't restored (`CACHE_SNAPSHOT_MAX_AGE_SECS`).
+fn max_age_secs() -> i64 {
+    match env::var("CACHE_SNAPSHOT_MAX_AGE_SECS") {
+        Ok(val) => val.parse().unwrap_or(DEFAULT_MAX_AGE_SECS),
+        Err(_) => DEFAULT_MAX_AGE_SECS,
+    }
+}
+
+async fn restore<K, V>(cache: &Cache<K, V>)
+where
+    K: Eq + Hash + Clone + Debug + Serialize + DeserializeOwned,
+    V: Clone + Debug + Serialize + DeserializeOwned,
+{
+    let entries: Vec<SnapshotEntry<K, V>> = match json::load_json(&snapshot_file(cache.name())) {
+        Some(entries) => entries,
+        None => return,
+    };
+    let cutoff = Utc::now() - ChronoDuration::seconds(max_age_secs());
+    let entries = entries.into_iter().filter(|entry| entry.cached_at > cutoff).collect();
+    let restored = cache.restore(entries).await;
+    println!("[CACHE] Restored {} entries of {}", restored, cache.name());
+}
+
+/// Write the Marvin caches to the data directory.
+/// The Toggl caches aren't kept: the /me warmup fills them before the first request.
+pub async fn save_all() {
+    categories::save();
+    save(&MARVIN_LABEL_CACHE).await;
+}
+
+/// Load the Marvin caches from their last snapshot. Restored entries are served as
+/// they are until they're revalidated.
+pub async fn restore_all() {
+    categories::restore(max_age_secs());
+    restore(&MARVIN_LABEL_CACHE).await;
+}
+
+/// Refresh the restored Marvin labels and category tree. Until then the restored
+/// values are served; labels that can't be refreshed are dropped.
+async fn revalidate_marvin() -> Result<(), WebhookError> {
+    categories::revalidate().await?;
+
+    if !MARVIN_LABEL_CACHE.restored_keys().await.is_empty() {
+        let marvin_client = marvin_client_from_env()?;
+        for label in marvin_client.get_labels().await? {
+            MARVIN_LABEL_CACHE.put(label.id, label.title).await;
+        }
+        MARVIN_LABEL_CACHE.drop_restored().await;
+    }
+    Ok(())
+}
+
+/// Revalidate the restored Marvin caches, retrying with a growing delay until it
+/// works, so restored values aren't served for longer than Marvin is unreachable.
+async fn revalidate_marvin_until_done() {
+    let mut retry_secs = REVALIDATE_RETRY_SECS;
+    while let Err(err) = revalidate_marvin().await {
+        eprintln!(
+            "[CACHE] Could not revalidate Marvin caches, retrying in {}s: {}",
+            retry_secs, err
+        );
+        sleep(Duration::from_secs(retry_secs)).await;
+        retry_secs = (retry_secs * 2).min(REVALIDATE_MAX_RETRY_SECS);
+    }
+}
+
+/// Revalidate the restored Marvin caches in the background, then save a snapshot of
+/// them every `CACHE_SNAPSHOT_INTERVAL_SECS` (default 300; 0 only saves on shutdown).
+pub async fn run_snapshots() {
+    tokio::spawn(revalidate_marvin_until_done());
+
+    let interval = match env::var("CACHE_SNAPSHOT_INTERVAL_SECS") {
+        Ok(val) => val.parse().unwrap_or(DEFAULT_INTERVAL_SECS),
+        Err(_) => DEFAULT_INTERVAL_SECS,
+    };
+    if interval == 0 {
+        return;
+    }
+    loop {
+        sleep(Duration::from_secs(interval)).await;
+        save_all().await;
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{
+    cache::cache::{
+        TOGGL_CLIENT_CACHE, TOGGL_PROJECT_CACHE, TOGGL_TAG_CACHE, TOGGL_TASK_CACHE,
+        log_toggl_cache_state,
+    },
+    jobs::error::WebhookError,
+    routes::marvin_webhooks::toggl_client_from_env,
+    toggl_api::responses::MeResponse,
+};
+
+/// Fill the Toggl caches from a `get_me(Some(true))` response.
+pub async fn populate_toggl_caches(me_response: MeResponse) {
+    println!("Initializing Toggl caches from /me response...");
+
+    // Cache clients
+    if let Some(clients) = me_response.clients {
+        println!("Caching {} clients", clients.len());
+        for client in clients {
+            TOGGL_CLIENT_CACHE.put(client.name, client.id).await;
+        }
+    }
+
+    // Cache projects (keyed by client_id + name)
+    if let Some(projects) = me_response.projects {
+        println!("Caching {} projects", projects.len());
+        for project in projects {
+            if let Some(client_id) = project.client_id {
+                TOGGL_PROJECT_CACHE.put((client_id, project.name), project.id).await;
+            }
+        }
+    }
+
+    // Cache tasks (keyed by project_id + name)
+    if let Some(tasks) = me_response.tasks {
+        println!("Caching {} tasks", tasks.len());
+        for task in tasks {
+            if let Some(project_id) = task.project_id {
+                TOGGL_TASK_CACHE.put((project_id, task.name), task.id).await;
+            }
+        }
+    }
+
+    // Cache tags
+    if let Some(tags) = me_response.tags {
+        println!("Caching {} tags", tags.len());
+        for tag in tags {
+            TOGGL_TAG_CACHE.put(tag.name, tag.id).await;
+        }
+    }
+}
+
+/// Run the startup warmup again: empty the Toggl caches and fill them from /me.
+pub async fn refresh_toggl_caches() -> Result<(), WebhookError> {
+    let toggl_client = toggl_client_from_env()?;
+    let me_response = toggl_client.get_me(Some(true)).await?;
+    TOGGL_CLIENT_CACHE.clear().await;
+    TOGGL_PROJECT_CACHE.clear().await;
+    TOGGL_TASK_CACHE.clear().await;
+    TOGGL_TAG_CACHE.clear().await;
+    populate_toggl_caches(me_response).await;
+    log_toggl_cache_state().await;
+    Ok(())
+}
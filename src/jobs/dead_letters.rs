@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::{
+    jobs::queue::{Job, JobKind},
+    store::json,
+};
+
+/// File (inside the data directory) the dead letters are persisted to.
+const DEAD_LETTER_FILE: &str = "dead_letters.json";
+
+/// A webhook that could not be processed, kept so it can be replayed later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    /// The job that gave up on this payload
+    pub job_id: u64,
+    pub kind: JobKind,
+    pub attempts: u32,
+    /// Error messages, outermost first (e.g. "Toggl API request failed", "HTTP error: ...")
+    pub error_chain: Vec<String>,
+    /// Unix milliseconds
+    pub failed_at: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DeadLetterState {
+    next_id: u64,
+    letters: Vec<DeadLetter>,
+}
+
+/// Durable store of failed webhook payloads.
+pub struct DeadLetterStore {
+    state: Mutex<DeadLetterState>,
+}
+
+pub static DEAD_LETTERS: LazyLock<DeadLetterStore> = LazyLock::new(|| {
+    let state: DeadLetterState = json::load_json_or_set_aside(DEAD_LETTER_FILE).unwrap_or_default();
+    println!("[DEAD LETTERS] Loaded {} dead letters", state.letters.len());
+    DeadLetterStore {
+        state: Mutex::new(state),
+    }
+});
+
+impl DeadLetterStore {
+    fn persist(state: &DeadLetterState) -> io::Result<()> {
+        json::save_json(DEAD_LETTER_FILE, state)
+    }
+
+    /// Store the payload of a job that failed for good.
+    pub fn add(&self, job: &Job, error_chain: Vec<String>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.letters.push(DeadLetter {
+            id,
+            job_id: job.id,
+            kind: job.kind.clone(),
+            attempts: job.attempts,
+            error_chain,
+            failed_at: chrono::Utc::now().timestamp_millis(),
+        });
+        if let Err(err) = Self::persist(&state) {
+            eprintln!("[DEAD LETTERS] Could not persist dead letters: {}", err);
+        }
+        println!("[DEAD LETTERS] Job {} stored as dead letter {}", job.id, id);
+        id
+    }
+
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.state.lock().unwrap().letters.clone()
+    }
+
+    pub fn get(&self, id: u64) -> Option<DeadLetter> {
+        let state = self.state.lock().unwrap();
+        state.letters.iter().find(|letter| letter.id == id).cloned()
+    }
+
+    /// Drop a dead letter, e.g. once it has been replayed.
+    pub fn remove(&self, id: u64) -> Option<DeadLetter> {
+        let mut state = self.state.lock().unwrap();
+        let index = state.letters.iter().position(|letter| letter.id == id)?;
+        let letter = state.letters.remove(index);
+        if let Err(err) = Self::persist(&state) {
+            eprintln!("[DEAD LETTERS] Could not persist dead letters: {}", err);
+        }
+        Some(letter)
+    }
+}
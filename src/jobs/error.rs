@@ -0,0 +1,51 @@
+use reqwest::StatusCode;
+use std::error::Error as _;
+use thiserror::Error;
+
+use crate::{api::error::ApiError, toggl_api::error::TogglError};
+
+/// Errors that can occur while processing a queued webhook
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("Marvin API request failed")]
+    Marvin(#[from] ApiError),
+
+    #[error("Toggl API request failed")]
+    Toggl(#[from] TogglError),
+
+    #[error("Missing configuration: {0}")]
+    Config(String),
+
+    #[error("Invalid or unexpected data: {0}")]
+    DataError(String),
+}
+
+impl WebhookError {
+    /// Messages of this error and everything that caused it, outermost first.
+    pub fn chain(&self) -> Vec<String> {
+        let mut chain = vec![self.to_string()];
+        let mut source = self.source();
+        while let Some(err) = source {
+            chain.push(err.to_string());
+            source = err.source();
+        }
+        chain
+    }
+
+    /// Whether Toggl rejected a cached ID of a project, task, tag or client it no longer has.
+    pub fn is_stale_reference(&self) -> bool {
+        matches!(self, WebhookError::Toggl(error) if error.is_stale_reference())
+    }
+
+    /// Whether trying again later could succeed: network problems, rate limits and
+    /// server errors are retried, anything else needs a human to look at it.
+    pub fn is_retryable(&self) -> bool {
+        let status = match self {
+            WebhookError::Marvin(ApiError::HttpError(_)) => return true,
+            WebhookError::Marvin(ApiError::StatusCodeError { status, .. }) => *status,
+            WebhookError::Toggl(error) => return error.is_outage(),
+            _ => return false,
+        };
+        status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+    }
+}
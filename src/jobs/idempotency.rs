@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, io,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::{jobs::queue::JobKind, store::json};
+
+/// Default for how long (in seconds) we remember a processed event
+const DEFAULT_WINDOW_SECS: i64 = 60 * 10;
+/// File (inside the data directory) the seen events are persisted to, next to the queue.
+const IDEMPOTENCY_FILE: &str = "idempotency.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SeenEvent {
+    /// Unix milliseconds
+    seen_at: i64,
+    /// None while the job is still being queued
+    job_id: Option<u64>,
+}
+
+/// Remembers recently queued Marvin events so that repeated deliveries of the
+/// same event (Marvin retrying, or several devices firing the same webhook)
+/// are only acted on once, also across restarts.
+pub struct IdempotencyStore {
+    seen: Mutex<HashMap<String, SeenEvent>>,
+    /// Held while writing, so snapshots reach the disk in the order they were taken
+    write: Mutex<()>,
+    window_ms: i64,
+}
+
+pub static IDEMPOTENCY: LazyLock<IdempotencyStore> = LazyLock::new(|| {
+    let window_secs = match env::var("IDEMPOTENCY_WINDOW_SECS") {
+        Ok(val) => val.parse().unwrap_or_else(|_| {
+            eprintln!("IDEMPOTENCY_WINDOW_SECS is not a number, using the default");
+            DEFAULT_WINDOW_SECS
+        }),
+        Err(_) => DEFAULT_WINDOW_SECS,
+    };
+    IdempotencyStore {
+        seen: Mutex::new(json::load_json(IDEMPOTENCY_FILE).unwrap_or_default()),
+        write: Mutex::new(()),
+        window_ms: window_secs * 1000,
+    }
+});
+
+/// Key identifying a single Marvin event: the task, what happened to it, and
+/// when. `updatedAt` and the last tracked timestamp change on every real
+/// start/stop, but stay the same when Marvin re-sends an event.
+pub fn idempotency_key(kind: &JobKind) -> String {
+    let (event, task) = match kind {
+        JobKind::StartTracking(task) => ("start", task),
+        JobKind::StopTracking(task) => ("stop", task),
+        JobKind::Backfill(request) => {
+            return format!(
+                "backfill:{}:{}:{}",
+                request.start_date, request.end_date, request.dry_run
+            );
+        }
+        JobKind::TomatoTimer(timer) => {
+            return format!(
+                "tomato:{}:{}:{}:{}",
+                timer.timer_id.unwrap_or_default(),
+                timer.cycle,
+                timer.is_work,
+                timer.done
+            );
+        }
+    };
+    format!(
+        "{}:{}:{}:{}",
+        task.id,
+        event,
+        task.updated_at,
+        task.times.last().copied().unwrap_or_default()
+    )
+}
+
+impl IdempotencyStore {
+    /// Return the job that already handled `key` within the window, or call
+    /// `enqueue` and remember its job for `key`.
+    /// The second value is true if `key` was a duplicate; the job is None if the
+    /// first delivery is still being queued.
+    pub fn get_or_insert_with<F>(&self, key: String, enqueue: F) -> io::Result<(Option<u64>, bool)>
+    where
+        F: FnOnce() -> io::Result<u64>,
+    {
+        let now = chrono::Utc::now().timestamp_millis();
+        {
+            let mut seen = self.seen.lock().unwrap();
+            seen.retain(|_, event| now - event.seen_at < self.window_ms);
+
+            if let Some(event) = seen.get(&key) {
+                println!("[IDEMPOTENCY] Duplicate event {} (job {:?})", key, event.job_id);
+                return Ok((event.job_id, true));
+            }
+            // Reserve the key so deliveries arriving while we queue are duplicates too
+            seen.insert(key.clone(), SeenEvent { seen_at: now, job_id: None });
+        }
+
+        let job_id = match enqueue() {
+            Ok(job_id) => job_id,
+            Err(err) => {
+                self.seen.lock().unwrap().remove(&key);
+                return Err(err);
+            }
+        };
+        if let Some(event) = self.seen.lock().unwrap().get_mut(&key) {
+            event.job_id = Some(job_id);
+        }
+        self.persist();
+        Ok((Some(job_id), false))
+    }
+
+    fn persist(&self) {
+        let _write = self.write.lock().unwrap();
+        let snapshot = self.seen.lock().unwrap().clone();
+        if let Err(err) = json::save_json(IDEMPOTENCY_FILE, &snapshot) {
+            eprintln!("[IDEMPOTENCY] Could not persist seen events: {}", err);
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod dead_letters;
+pub mod error;
+pub mod idempotency;
+pub mod queue;
+pub mod worker;
@@ -0,0 +1,373 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    env, io,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::sleep};
+
+use crate::{
+    models::{tasks::Task, timers::TomatoTimer},
+    store::json,
+    sync::backfill::BackfillRequest,
+};
+
+/// File (inside the data directory) the queue is persisted to.
+const QUEUE_FILE: &str = "jobs.json";
+/// Default number of attempts before a job is given up on
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+/// Default delay before the first retry, doubled on every further attempt
+const DEFAULT_BACKOFF_SECS: u64 = 5;
+/// Default time a job is held back before it may run, so that an event Marvin
+/// sent earlier but which reached us later can still overtake it
+const DEFAULT_REORDER_WINDOW_MS: i64 = 2000;
+/// Upper bound for the retry delay
+const MAX_BACKOFF_SECS: u64 = 60 * 10;
+/// Number of finished (succeeded or failed) jobs kept around for `GET /jobs`
+const FINISHED_HISTORY: usize = 100;
+
+/// The work a job has to do, together with the webhook payload it was created from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum JobKind {
+    StartTracking(Task),
+    StopTracking(Task),
+    Backfill(BackfillRequest),
+    TomatoTimer(TomatoTimer),
+}
+
+/// Jobs are processed by one worker per lane, so a long backfill doesn't hold up
+/// the start/stop webhooks queued behind it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    /// Webhooks that drive the Toggl tracking state machine
+    Tracking,
+    Backfill,
+}
+
+impl Lane {
+    fn index(self) -> usize {
+        match self {
+            Lane::Tracking => 0,
+            Lane::Backfill => 1,
+        }
+    }
+}
+
+impl JobKind {
+    pub fn lane(&self) -> Lane {
+        match self {
+            JobKind::Backfill(_) => Lane::Backfill,
+            _ => Lane::Tracking,
+        }
+    }
+
+    /// When the event happened according to Marvin (unix milliseconds), if the
+    /// job comes from a Marvin event. The last entry of `times` is the start or
+    /// stop being reported; `updatedAt` is the fallback for tasks without times.
+    pub fn event_at(&self) -> Option<i64> {
+        let task = match self {
+            JobKind::StartTracking(task) | JobKind::StopTracking(task) => task,
+            JobKind::Backfill(_) | JobKind::TomatoTimer(_) => return None,
+        };
+        Some(task.times.last().copied().unwrap_or(task.updated_at))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting to be picked up (possibly after a failed attempt)
+    Pending,
+    /// Currently being processed by the worker
+    Running,
+    Succeeded,
+    /// Gave up after `max_attempts`
+    Failed,
+}
+
+/// A queued webhook. Timestamps are unix milliseconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub created_at: i64,
+    pub updated_at: i64,
+    /// When the event happened according to Marvin; jobs run in this order
+    #[serde(default)]
+    pub event_at: i64,
+    /// Earliest time the next attempt may run
+    pub next_attempt_at: i64,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub result: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueState {
+    next_id: u64,
+    /// All known jobs, ordered by id (= arrival order)
+    jobs: Vec<Job>,
+    /// Bumped on every change, so an older state is never written over a newer one
+    #[serde(skip)]
+    version: u64,
+}
+
+/// The queue as it was at one version, serialized under the state lock and written
+/// to disk after the lock is released.
+struct Snapshot {
+    version: u64,
+    text: String,
+}
+
+/// Durable queue of webhook jobs, processed in the order the events happened in Marvin.
+/// Every mutation is written to disk before it is acknowledged, so jobs survive restarts.
+pub struct JobQueue {
+    state: Mutex<QueueState>,
+    /// Version of the state last written to disk
+    written: Mutex<u64>,
+    /// One per lane
+    notify: [Notify; 2],
+    max_attempts: u32,
+    backoff_secs: u64,
+    reorder_window_ms: i64,
+}
+
+pub static JOB_QUEUE: LazyLock<JobQueue> = LazyLock::new(JobQueue::load);
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(val) => match val.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                eprintln!("{} is not a valid value, using the default", name);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+impl JobQueue {
+    /// Load the queue from disk. Jobs that were running when we went down are
+    /// put back to pending so they get retried. A file that can't be loaded is
+    /// moved aside rather than overwritten.
+    fn load() -> Self {
+        let mut state: QueueState = json::load_json_or_set_aside(QUEUE_FILE).unwrap_or_default();
+        for job in state.jobs.iter_mut() {
+            if job.status == JobStatus::Running {
+                job.status = JobStatus::Pending;
+            }
+        }
+        let pending = state
+            .jobs
+            .iter()
+            .filter(|job| job.status == JobStatus::Pending)
+            .count();
+        println!("[JOBS] Loaded {} jobs ({} pending)", state.jobs.len(), pending);
+
+        Self {
+            state: Mutex::new(state),
+            written: Mutex::new(0),
+            notify: [Notify::new(), Notify::new()],
+            max_attempts: env_or("JOB_MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS).max(1),
+            backoff_secs: env_or("JOB_BACKOFF_SECS", DEFAULT_BACKOFF_SECS),
+            reorder_window_ms: env_or("JOB_REORDER_WINDOW_MS", DEFAULT_REORDER_WINDOW_MS),
+        }
+    }
+
+    /// Serialize the state after a change. Call with the state lock held, and
+    /// `persist` the result once it's released.
+    fn snapshot(state: &mut QueueState) -> io::Result<Snapshot> {
+        state.version += 1;
+        let text = serde_json::to_string_pretty(state).map_err(io::Error::other)?;
+        Ok(Snapshot { version: state.version, text })
+    }
+
+    /// Write a snapshot unless a newer one has been written in the meantime.
+    fn persist(&self, snapshot: io::Result<Snapshot>) -> io::Result<()> {
+        let snapshot = snapshot?;
+        let mut written = self.written.lock().unwrap();
+        if snapshot.version <= *written {
+            return Ok(());
+        }
+        json::save_text(QUEUE_FILE, &snapshot.text)?;
+        *written = snapshot.version;
+        Ok(())
+    }
+
+    /// Add a job to the back of the queue and wake the worker.
+    /// Returns the id of the new job once it has been persisted.
+    pub fn enqueue(&self, kind: JobKind) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let now = now_ms();
+        state.next_id += 1;
+        let id = state.next_id;
+        let event_at = kind.event_at().unwrap_or(now);
+        let lane = kind.lane();
+        state.jobs.push(Job {
+            id,
+            kind,
+            status: JobStatus::Pending,
+            attempts: 0,
+            max_attempts: self.max_attempts,
+            created_at: now,
+            updated_at: now,
+            event_at,
+            next_attempt_at: now + self.reorder_window_ms,
+            last_error: None,
+            result: None,
+        });
+        let snapshot = Self::snapshot(&mut state);
+        drop(state);
+        if let Err(err) = self.persist(snapshot) {
+            // Don't acknowledge something we couldn't store
+            let mut state = self.state.lock().unwrap();
+            state.jobs.retain(|job| job.id != id);
+            state.version += 1;
+            return Err(err);
+        }
+
+        println!("[JOBS] Queued job {}", id);
+        self.notify[lane.index()].notify_one();
+        Ok(id)
+    }
+
+    /// Snapshot of every job we know about, oldest first.
+    pub fn list(&self) -> Vec<Job> {
+        self.state.lock().unwrap().jobs.clone()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        let state = self.state.lock().unwrap();
+        state.jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Wait for the job at the head of the queue to become due, mark it as
+    /// running and return it. The head is the pending job whose event happened
+    /// first in Marvin (ties broken by arrival order), so events that reach us
+    /// out of order within the reorder window are still applied in order.
+    /// A job waiting for its retry holds back everything after it in its lane.
+    pub async fn next_job(&self, lane: Lane) -> Job {
+        let notify = &self.notify[lane.index()];
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = now_ms();
+                match state
+                    .jobs
+                    .iter_mut()
+                    .filter(|job| job.status == JobStatus::Pending && job.kind.lane() == lane)
+                    .min_by_key(|job| (job.event_at, job.id))
+                {
+                    Some(job) if job.next_attempt_at <= now => {
+                        job.status = JobStatus::Running;
+                        job.attempts += 1;
+                        job.updated_at = now;
+                        let job = job.clone();
+                        let snapshot = Self::snapshot(&mut state);
+                        drop(state);
+                        if let Err(err) = self.persist(snapshot) {
+                            eprintln!("[JOBS] Could not persist queue: {}", err);
+                        }
+                        return job;
+                    }
+                    Some(job) => Some(Duration::from_millis((job.next_attempt_at - now) as u64)),
+                    None => None,
+                }
+            };
+
+            match wait {
+                Some(delay) => {
+                    tokio::select! {
+                        _ = sleep(delay) => {},
+                        _ = notify.notified() => {},
+                    }
+                }
+                None => notify.notified().await,
+            }
+        }
+    }
+
+    /// Record a successful attempt.
+    pub fn complete(&self, id: u64, result: String) {
+        self.finish(id, |job, _| {
+            job.status = JobStatus::Succeeded;
+            job.result = Some(result);
+        });
+    }
+
+    /// Record a failed attempt. Retryable failures are retried with exponential
+    /// backoff until the job has used up its attempts, after which (or straight away
+    /// for non-retryable failures) it is marked as failed.
+    /// Returns the updated job.
+    pub fn fail(&self, id: u64, error: String, retryable: bool) -> Option<Job> {
+        let backoff_secs = self.backoff_secs;
+        self.finish(id, |job, now| {
+            job.last_error = Some(error);
+            if !retryable {
+                println!("[JOBS] Job {} failed with a non-retryable error", job.id);
+                job.status = JobStatus::Failed;
+                return;
+            }
+            if job.attempts >= job.max_attempts {
+                println!("[JOBS] Job {} failed permanently after {} attempts", job.id, job.attempts);
+                job.status = JobStatus::Failed;
+                return;
+            }
+            let delay = backoff_secs
+                .saturating_mul(1 << (job.attempts - 1).min(16))
+                .min(MAX_BACKOFF_SECS);
+            println!(
+                "[JOBS] Job {} failed (attempt {}/{}), retrying in {}s",
+                job.id, job.attempts, job.max_attempts, delay
+            );
+            job.status = JobStatus::Pending;
+            job.next_attempt_at = now + (delay * 1000) as i64;
+        })
+    }
+
+    fn finish<F>(&self, id: u64, update: F) -> Option<Job>
+    where
+        F: FnOnce(&mut Job, i64),
+    {
+        let mut state = self.state.lock().unwrap();
+        let now = now_ms();
+        let job = state.jobs.iter_mut().find(|job| job.id == id)?;
+        update(job, now);
+        job.updated_at = now;
+        let job = job.clone();
+
+        // Only keep a bounded history of finished jobs
+        let finished = state
+            .jobs
+            .iter()
+            .filter(|job| matches!(job.status, JobStatus::Succeeded | JobStatus::Failed))
+            .count();
+        let mut to_drop = finished.saturating_sub(FINISHED_HISTORY);
+        state.jobs.retain(|job| {
+            if to_drop > 0 && matches!(job.status, JobStatus::Succeeded | JobStatus::Failed) {
+                to_drop -= 1;
+                return false;
+            }
+            true
+        });
+
+        let snapshot = Self::snapshot(&mut state);
+        drop(state);
+        if let Err(err) = self.persist(snapshot) {
+            eprintln!("[JOBS] Could not persist queue: {}", err);
+        }
+
+        self.notify[job.kind.lane().index()].notify_one();
+        Some(job)
+    }
+}
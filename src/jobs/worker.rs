@@ -0,0 +1,50 @@
+use crate::{
+    jobs::{
+        dead_letters::DEAD_LETTERS,
+        queue::{JOB_QUEUE, JobKind, JobStatus, Lane},
+    },
+    routes::marvin_webhooks::{process_start_tracking, process_stop_tracking},
+    sync::backfill::run_backfill,
+    tracking::{lock::TRACKING_LOCK, pomodoro::process_tomato_timer},
+};
+
+/// Process the queued jobs of `lane` one at a time, forever.
+/// Spawned once per lane at startup.
+pub async fn run_worker(lane: Lane) {
+    println!("[JOBS] {:?} worker started", lane);
+    loop {
+        let job = JOB_QUEUE.next_job(lane).await;
+        println!("[JOBS] Running job {} (attempt {})", job.id, job.attempts);
+
+        let outcome = {
+            // Backfills take the tracking lock themselves, only while they create an entry
+            let _guard = match lane {
+                Lane::Tracking => Some(TRACKING_LOCK.lock().await),
+                Lane::Backfill => None,
+            };
+            match &job.kind {
+                JobKind::StartTracking(task) => process_start_tracking(task).await,
+                JobKind::StopTracking(task) => process_stop_tracking(task).await,
+                JobKind::Backfill(request) => run_backfill(request)
+                    .await
+                    .map(|report| serde_json::to_string(&report).unwrap_or_default()),
+                JobKind::TomatoTimer(timer) => process_tomato_timer(timer).await,
+            }
+        };
+
+        match outcome {
+            Ok(result) => {
+                println!("[JOBS] Job {} succeeded: {}", job.id, result);
+                JOB_QUEUE.complete(job.id, result);
+            }
+            Err(err) => {
+                let chain = err.chain();
+                println!("[JOBS] Job {} failed: {}", job.id, chain.join(": "));
+                let job = JOB_QUEUE.fail(job.id, chain.join(": "), err.is_retryable());
+                if let Some(job) = job.filter(|job| job.status == JobStatus::Failed) {
+                    DEAD_LETTERS.add(&job, chain);
+                }
+            }
+        }
+    }
+}
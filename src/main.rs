@@ -1,4 +1,4 @@
-use std::{env, sync::{atomic::AtomicI64, Arc, LazyLock, Mutex, OnceLock}};
+use std::{env, sync::{atomic::AtomicI64, LazyLock, Mutex, OnceLock}};
 
 use axum::{
     Router,
@@ -14,13 +14,16 @@ mod toggl_api;
 mod routes; // bring in our `routes` module
 mod models;
 mod cache;
+mod jobs;
+mod retry;
+mod store;
+mod sync;
+mod tracking;
 
-use cache::cache::{
-    TOGGL_CLIENT_CACHE, TOGGL_PROJECT_CACHE, TOGGL_TASK_CACHE, TOGGL_TAG_CACHE,
-    cache_put, log_toggl_cache_state,
-};
+use cache::cache::log_toggl_cache_state;
 
 static WORKSPACE_ID: OnceLock<i64> = OnceLock::new();
+static USER_TIMEZONE: OnceLock<chrono_tz::Tz> = OnceLock::new();
 static LEISURE_BALANCE: AtomicI64 = AtomicI64::new(0);
 static LEISURE_RATE: LazyLock<Mutex<f64>> = LazyLock::new(|| {Mutex::new(1.0/3.0)});
 
@@ -50,59 +53,43 @@ async fn main() {
     let workspace_id = me_response.default_workspace_id.unwrap();
     WORKSPACE_ID.set(workspace_id).unwrap();
 
-    // Populate caches from the /me response
-    println!("Initializing Toggl caches from /me response...");
-
-    // Cache clients
-    if let Some(clients) = me_response.clients {
-        println!("Caching {} clients", clients.len());
-        for client in clients {
-            cache_put(Arc::clone(&*TOGGL_CLIENT_CACHE), client.name, client.id);
-        }
-    }
-
-    // Cache projects (keyed by client_id + name)
-    if let Some(projects) = me_response.projects {
-        println!("Caching {} projects", projects.len());
-        for project in projects {
-            if let Some(client_id) = project.client_id {
-                cache_put(
-                    Arc::clone(&*TOGGL_PROJECT_CACHE),
-                    (client_id, project.name),
-                    project.id,
-                );
-            }
-        }
-    }
-
-    // Cache tasks (keyed by project_id + name)
-    if let Some(tasks) = me_response.tasks {
-        println!("Caching {} tasks", tasks.len());
-        for task in tasks {
-            if let Some(project_id) = task.project_id {
-                cache_put(
-                    Arc::clone(&*TOGGL_TASK_CACHE),
-                    (project_id, task.name),
-                    task.id,
-                );
-            }
+    // Days (for splitting entries and leisure per day) begin at midnight in this timezone
+    let timezone = match me_response.timezone.as_deref().map(str::parse::<chrono_tz::Tz>) {
+        Some(Ok(timezone)) => timezone,
+        _ => {
+            eprintln!("Unknown Toggl timezone {:?}, using UTC", me_response.timezone);
+            chrono_tz::Tz::UTC
         }
-    }
-
-    // Cache tags
-    if let Some(tags) = me_response.tags {
-        println!("Caching {} tags", tags.len());
-        for tag in tags {
-            cache_put(Arc::clone(&*TOGGL_TAG_CACHE), tag.name, tag.id);
-        }
-    }
-
-    log_toggl_cache_state();
+    };
+    USER_TIMEZONE.set(timezone).unwrap();
+
+    // Fill the Toggl caches from the /me response, and start the Marvin caches from
+    // their last snapshot
+    cache::warmup::populate_toggl_caches(me_response).await;
+    cache::snapshot::restore_all().await;
+    log_toggl_cache_state().await;
+
+    // Refresh the restored Marvin caches and snapshot all caches periodically
+    tokio::spawn(cache::snapshot::run_snapshots());
+    // Process queued webhooks in the background; backfills get their own worker
+    tokio::spawn(jobs::worker::run_worker(jobs::queue::Lane::Tracking));
+    tokio::spawn(jobs::worker::run_worker(jobs::queue::Lane::Backfill));
+    // Mirror entries started outside Marvin back into Marvin (if enabled)
+    tokio::spawn(sync::reverse::run_reverse_sync());
+    // Stop entries that were left running (if limits are configured)
+    tokio::spawn(tracking::watchdog::run_auto_stop());
+    // Stop entries once heartbeats go quiet (if enabled)
+    tokio::spawn(tracking::idle::run_idle_detection());
+    // Check the running entry against each time block as it starts (if enabled)
+    tokio::spawn(tracking::adherence::run_block_checks());
 
     // Build our application by composing routes
     let app = Router::new()
         .merge(routes::marvin_webhooks::router()) // Our Marvin webhook routes
         .merge(routes::third_time::router()) // Our Third Time webhook routes
+        .merge(routes::toggl_webhooks::router()) // Toggl Track webhook subscription
+        .merge(routes::heartbeat::router()) // Activity heartbeats for idle detection
+        .merge(routes::admin::router()) // Cache inspection and invalidation
     // Example of an entirely different route: 
         .route("/health", get(|| async { "OK" }))
         // Add a CORS layer so Marvin’s client can POST from https://app.amazingmarvin.com
@@ -143,4 +130,7 @@ async fn shutdown_signal() {
         _ = ctrl_c => {},
         _ = terminate => {},
     }
+
+    // Keep the caches warm for the next start
+    cache::snapshot::save_all().await;
 }
@@ -0,0 +1,20 @@
+use reqwest::Response;
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    time::Duration,
+};
+
+/// The delay from the first of `headers` that holds a number of seconds,
+/// e.g. `Retry-After`.
+pub fn retry_after(resp: &Response, headers: &[&str]) -> Option<Duration> {
+    headers.iter().find_map(|name| {
+        let secs: u64 = resp.headers().get(*name)?.to_str().ok()?.trim().parse().ok()?;
+        Some(Duration::from_secs(secs))
+    })
+}
+
+/// Random extra delay of up to a quarter of `delay`, so retries don't line up.
+pub fn jitter(delay: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    delay.mul_f64((random % 1000) as f64 / 4000.0)
+}
@@ -0,0 +1,125 @@
+use axum::{
+    Json, Router,
+    extract::Path,
+    http::StatusCode,
+    middleware,
+    routing::{delete, get, post},
+};
+use serde::Serialize;
+
+use crate::cache::{
+    cache::{
+        CacheKey, CacheReport, MARVIN_LABEL_CACHE, TOGGL_CLIENT_CACHE, TOGGL_PROJECT_CACHE,
+        TOGGL_TAG_CACHE, TOGGL_TASK_CACHE,
+    },
+    categories::{self, CATEGORY_TREE_NAME, CategoryTreeReport},
+    warmup,
+};
+use crate::routes::auth::require_token;
+
+/// Run `$body` with `$cache` bound to the cache called `$name`.
+/// Evaluates to None if there's no such cache.
+macro_rules! with_cache {
+    ($name:expr, $cache:ident => $body:expr) => {
+        match $name {
+            "marvin_labels" => Some({
+                let $cache = &*MARVIN_LABEL_CACHE;
+                $body
+            }),
+            "toggl_clients" => Some({
+                let $cache = &*TOGGL_CLIENT_CACHE;
+                $body
+            }),
+            "toggl_projects" => Some({
+                let $cache = &*TOGGL_PROJECT_CACHE;
+                $body
+            }),
+            "toggl_tasks" => Some({
+                let $cache = &*TOGGL_TASK_CACHE;
+                $body
+            }),
+            "toggl_tags" => Some({
+                let $cache = &*TOGGL_TAG_CACHE;
+                $body
+            }),
+            _ => None,
+        }
+    };
+}
+
+/// Router for inspecting and invalidating the caches.
+pub fn router() -> Router {
+    Router::new()
+        .route("/admin/cache", get(list_caches))
+        .route("/admin/cache/refresh", post(refresh_caches))
+        .route("/admin/cache/{name}", delete(clear_cache))
+        .route("/admin/cache/{name}/{key}", delete(remove_cache_entry))
+        .layer(middleware::from_fn(|req, next| require_token("ADMIN_TOKEN", req, next)))
+}
+
+/// GET /admin/cache body
+#[derive(Debug, Serialize)]
+struct CachesResponse {
+    caches: Vec<CacheReport>,
+    categories: CategoryTreeReport,
+}
+
+/// GET /admin/cache
+/// Every cache's entries with their ages, and hit/miss counters.
+async fn list_caches() -> Json<CachesResponse> {
+    Json(CachesResponse {
+        caches: vec![
+            MARVIN_LABEL_CACHE.report().await,
+            TOGGL_CLIENT_CACHE.report().await,
+            TOGGL_PROJECT_CACHE.report().await,
+            TOGGL_TASK_CACHE.report().await,
+            TOGGL_TAG_CACHE.report().await,
+        ],
+        categories: categories::report(),
+    })
+}
+
+/// DELETE /admin/cache/{name}
+/// Empty one cache; its entries are looked up again when they're needed.
+async fn clear_cache(Path(name): Path<String>) -> Result<String, StatusCode> {
+    let cleared = if name == CATEGORY_TREE_NAME {
+        categories::clear()
+    } else {
+        with_cache!(name.as_str(), cache => cache.clear().await).ok_or(StatusCode::NOT_FOUND)?
+    };
+    println!("[ADMIN] Cleared {} entries of {}", cleared, name);
+    Ok(format!("Cleared {} entries", cleared))
+}
+
+/// DELETE /admin/cache/{name}/{key}
+/// Forget one entry. Keys of (ID, name) caches are written "ID:name".
+async fn remove_cache_entry(
+    Path((name, key)): Path<(String, String)>,
+) -> Result<String, StatusCode> {
+    let removed = if name == CATEGORY_TREE_NAME {
+        categories::remove(&key).is_some()
+    } else {
+        with_cache!(name.as_str(), cache => match CacheKey::parse_key(&key) {
+            Some(key) => cache.remove(&key).await.is_some(),
+            None => return Err(StatusCode::BAD_REQUEST),
+        })
+        .ok_or(StatusCode::NOT_FOUND)?
+    };
+    if !removed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    println!("[ADMIN] Removed '{}' from {}", key, name);
+    Ok("Entry removed".to_string())
+}
+
+/// POST /admin/cache/refresh
+/// Run the startup warmup from Toggl's /me again.
+async fn refresh_caches() -> Result<String, StatusCode> {
+    match warmup::refresh_toggl_caches().await {
+        Ok(()) => Ok("Toggl caches refreshed".to_string()),
+        Err(err) => {
+            println!("Cache refresh failed: {}", err.chain().join(": "));
+            Err(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
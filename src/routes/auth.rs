@@ -0,0 +1,44 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::{env, hint::black_box};
+
+/// Compare two byte strings in time that only depends on their length, so the
+/// response time doesn't tell how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y));
+    black_box(diff) == 0
+}
+
+/// Middleware checking that the "Authorization" header matches the token in the
+/// `token_var` environment variable. Each router has its own variable, e.g.
+/// `.layer(middleware::from_fn(|req, next| require_token("ADMIN_TOKEN", req, next)))`.
+pub async fn require_token(
+    token_var: &'static str,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let token = match env::var(token_var) {
+        Ok(val) => val,
+        Err(_) => {
+            eprintln!("{} is not set!", token_var);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    match req.headers().get("Authorization") {
+        Some(header_value) if constant_time_eq(header_value.as_bytes(), token.as_bytes()) => {
+            Ok(next.run(req).await)
+        }
+        _ => {
+            eprintln!("Unauthorized request to {} (checked against {})", req.uri().path(), token_var);
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use axum::{
+    Json, Router,
+    body::Bytes,
+    http::StatusCode,
+    middleware,
+    routing::post,
+};
+use serde::Deserialize;
+
+use crate::{
+    routes::auth::require_token,
+    tracking::idle::{self, Heartbeat},
+};
+
+/// Router for activity heartbeats from local agents (desktop, browser extension).
+pub fn router() -> Router {
+    Router::new()
+        .route("/heartbeat", post(heartbeat).get(get_heartbeat))
+        .layer(middleware::from_fn(|req, next| require_token("HEARTBEAT_TOKEN", req, next)))
+}
+
+/// Optional POST /heartbeat body
+#[derive(Debug, Default, Deserialize)]
+struct HeartbeatRequest {
+    #[serde(default)]
+    source: Option<String>,
+}
+
+/// POST /heartbeat
+/// The body may be empty.
+async fn heartbeat(body: Bytes) -> Result<String, StatusCode> {
+    let request: HeartbeatRequest = if body.is_empty() {
+        HeartbeatRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?
+    };
+    idle::record_heartbeat(request.source);
+    Ok("OK".to_string())
+}
+
+/// GET /heartbeat
+/// The last heartbeat received, if any.
+async fn get_heartbeat() -> Json<Option<Heartbeat>> {
+    Json(idle::last_heartbeat())
+}
@@ -1,176 +1,267 @@
 use axum::{
     Json, Router,
-    body::Body,
-    http::{Request, StatusCode},
-    middleware::{self, Next},
-    response::Response,
-    routing::post,
+    extract::{Path, Query},
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
 };
+use chrono::{NaiveDate, Utc};
 use regex::Regex;
 use serde::Deserialize;
 use serde_json::Value;
-use std::{env, sync::Arc, time::Duration};
-use tokio::time::{Sleep, sleep};
+use std::env;
 
 use crate::{
+    routes::auth::require_token,
     WORKSPACE_ID,
     api::{
         client::MarvinClient,
-        requests::{CreateProjectRequest, CreateTaskRequest},
+        docs::{MarvinDb, TypedDoc},
+        requests::{CreateProjectRequest, CreateTaskRequest, MarvinDoc},
     },
-    cache::cache::{
-        self, TOGGL_CLIENT_CACHE, TOGGL_PROJECT_CACHE, TOGGL_TASK_CACHE, cache_get, cache_put,
-        log_toggl_cache_state,
+    jobs::{
+        dead_letters::{DEAD_LETTERS, DeadLetter},
+        error::WebhookError,
+        idempotency::{IDEMPOTENCY, idempotency_key},
+        queue::{JOB_QUEUE, Job, JobKind, JobStatus},
+    },
+    cache::{
+        cache::{
+            self, TOGGL_CLIENT_CACHE, TOGGL_PROJECT_CACHE, TOGGL_TASK_CACHE,
+            log_toggl_cache_state,
+        },
+        categories::{self, CategoryInfo},
+        single_flight::IN_FLIGHT,
+    },
+    models::{
+        labels::Label,
+        tasks::{ProjectOrCategory, Task},
+        timers::TomatoTimer,
+    },
+    sync::{
+        backfill::BackfillRequest,
+        origin::{self, TrackAction},
     },
-    models::tasks::{ProjectOrCategory, Task},
     toggl_api::{
         client::{TogglClient, StopCondition},
         requests::{CreateClientRequest, CreateTagRequest},
     },
+    tracking::{
+        adherence::{self, AdherenceReport},
+        days,
+        estimate::{self, EstimateStatus},
+        resume,
+        watchdog::{AUTO_STOPS, AutoStop},
+    },
 };
 
 /// Removes timestamp prefixes like "11:55 am" or "6:10 pm" from the beginning of task names.
 /// Examples:
 ///   "11:55 am blah blah blah" -> "blah blah blah"
 ///   "6:10 pm Week 1: ISA design" -> "Week 1: ISA design"
-fn remove_timestamp_prefix(text: &str) -> String {
+pub fn remove_timestamp_prefix(text: &str) -> String {
     // Pattern matches: digits:digits followed by optional space and am/pm, then a space
     let re = Regex::new(r"^\d{1,2}:\d{2}\s*(?:am|pm|AM|PM)\s+").unwrap();
     re.replace(text, "").to_string()
 }
 
+/// Title of Marvin label `id`, or an empty string if there's no such label.
+async fn label_title(marvin_client: &MarvinClient, id: &str) -> Result<String, WebhookError> {
+    let key = id.to_string();
+    if let Some(label) = cache::MARVIN_LABEL_CACHE.get(&key).await {
+        return Ok(label);
+    }
+    // Concurrent misses share one listing of the labels
+    IN_FLIGHT
+        .run("marvin_labels", || async {
+            if cache::MARVIN_LABEL_CACHE.get(&key).await.is_some() {
+                return Ok(false);
+            }
+            let labels = marvin_client
+                .get_labels()
+                .await
+                .inspect_err(|err| println!("Error collecting labels: {}", err))?;
+            for l in labels {
+                cache::MARVIN_LABEL_CACHE.put(l.id, l.title).await;
+            }
+            Ok::<_, WebhookError>(true)
+        })
+        .await?;
+    Ok(cache::MARVIN_LABEL_CACHE.get(&key).await.unwrap_or_default())
+}
+
+/// Build a MarvinClient from `MARVIN_API_TOKEN` and `MARVIN_FULL_ACCESS_TOKEN`.
+pub fn marvin_client_from_env() -> Result<MarvinClient, WebhookError> {
+    let marvin_api_token = match env::var("MARVIN_API_TOKEN") {
+        Ok(val) => val,
+        Err(_) => {
+            eprintln!("MARVIN_API_TOKEN is not set!");
+            return Err(WebhookError::Config("MARVIN_API_TOKEN is not set".to_string()));
+        }
+    };
+
+    let marvin_full_access_token = match env::var("MARVIN_FULL_ACCESS_TOKEN") {
+        Ok(val) => val,
+        Err(_) => {
+            eprintln!("MARVIN_FULL_ACCESS_TOKEN is not set!");
+            return Err(WebhookError::Config("MARVIN_FULL_ACCESS_TOKEN is not set".to_string()));
+        }
+    };
+
+    Ok(MarvinClient::new(Some(marvin_api_token), Some(marvin_full_access_token)))
+}
+
+/// Build a TogglClient from `TOGGL_API_TOKEN`.
+pub fn toggl_client_from_env() -> Result<TogglClient, WebhookError> {
+    let toggl_api_token = match env::var("TOGGL_API_TOKEN") {
+        Ok(val) => val,
+        Err(_) => {
+            eprintln!("TOGGL_API_TOKEN is not set!");
+            return Err(WebhookError::Config("TOGGL_API_TOKEN is not set".to_string()));
+        }
+    };
+
+    Ok(TogglClient::new(toggl_api_token, "api_token".to_string()))
+}
+
+/// The default Toggl workspace, looked up at startup.
+pub fn workspace_id() -> Result<i64, WebhookError> {
+    match WORKSPACE_ID.get() {
+        Some(workspace_id) => Ok(*workspace_id),
+        None => Err(WebhookError::Config("Toggl workspace is not known yet".to_string())),
+    }
+}
+
 /// Resolved Toggl IDs from a Marvin task hierarchy.
 #[derive(Debug, Clone)]
-struct ResolvedTogglIds {
-    client_id: Option<i64>,
-    project_id: Option<i64>,
-    task_id: Option<i64>,
-    description: String,
-    tags: Vec<i64>,
+pub struct ResolvedTogglIds {
+    pub client_id: Option<i64>,
+    pub project_id: Option<i64>,
+    pub task_id: Option<i64>,
+    pub description: String,
+    pub tags: Vec<i64>,
+}
+
+/// Forget the cached Toggl IDs in `resolved` after Toggl rejected one of them,
+/// so the next resolve looks them up again.
+pub async fn evict_resolved(resolved: &ResolvedTogglIds) {
+    if let Some(client_id) = resolved.client_id {
+        evict_client(client_id).await;
+    }
+    if let Some(project_id) = resolved.project_id {
+        evict_project(project_id).await;
+    }
+    if let Some(task_id) = resolved.task_id {
+        TOGGL_TASK_CACHE.remove_where(|_, id| *id == task_id).await;
+    }
+    cache::TOGGL_TAG_CACHE.remove_where(|_, id| resolved.tags.contains(id)).await;
+}
+
+async fn evict_client(client_id: i64) {
+    TOGGL_CLIENT_CACHE.remove_where(|_, id| *id == client_id).await;
+    TOGGL_PROJECT_CACHE.remove_where(|(cid, _), _| *cid == client_id).await;
+}
+
+async fn evict_project(project_id: i64) {
+    TOGGL_PROJECT_CACHE.remove_where(|_, id| *id == project_id).await;
+    TOGGL_TASK_CACHE.remove_where(|(pid, _), _| *pid == project_id).await;
 }
 
 /// Resolves a Marvin Task to Toggl IDs by walking the parent hierarchy.
 /// If `create_if_missing` is true, creates missing clients/projects/tasks in Toggl.
 /// If false, returns None for IDs that don't exist.
-async fn resolve_marvin_task_to_toggl(
+/// If Toggl rejects a cached client or project on the way, it's forgotten and the
+/// task is resolved once more.
+pub async fn resolve_marvin_task_to_toggl(
+    payload: &Task,
+    marvin_client: &MarvinClient,
+    toggl_client: &TogglClient,
+    workspace_id: i64,
+    create_if_missing: bool,
+) -> Result<ResolvedTogglIds, WebhookError> {
+    let resolve = || {
+        resolve_once(payload, marvin_client, toggl_client, workspace_id, create_if_missing)
+    };
+    match resolve().await {
+        Err(error) if error.is_stale_reference() => {
+            println!("Toggl rejected cached IDs ({}), resolving again", error.chain().join(": "));
+            resolve().await
+        }
+        result => result,
+    }
+}
+
+async fn resolve_once(
     payload: &Task,
     marvin_client: &MarvinClient,
     toggl_client: &TogglClient,
     workspace_id: i64,
     create_if_missing: bool,
-) -> Result<ResolvedTogglIds, StatusCode> {
+) -> Result<ResolvedTogglIds, WebhookError> {
     println!(
         "=== resolve_marvin_task_to_toggl ===\nTask: '{}'\nParent ID: '{}'\ncreate_if_missing: {}",
         payload.title, payload.parent_id, create_if_missing
     );
-    log_toggl_cache_state();
-
-    // Walk parent hierarchy to collect parent names
-    let mut parent_id = payload.parent_id.clone();
-    let mut parents: Vec<String> = vec![];
-
-    while parent_id != "root" && parent_id != "unassigned" {
-        let parent = cache::cache_get(Arc::clone(&*cache::MARVIN_PROJECT_CACHE), &parent_id);
-        let parent = match parent {
-            Some(parent) => parent,
-            None => match marvin_client.read_doc(&parent_id).await {
-                Ok(doc) => {
-                    let title = match doc.extra.get("title") {
-                        Some(Value::String(title)) => title.clone(),
-                        _ => return Err(StatusCode::SERVICE_UNAVAILABLE),
-                    };
-                    let parent = match doc.extra.get("parentId") {
-                        Some(Value::String(parent)) => parent.clone(),
-                        _ => return Err(StatusCode::SERVICE_UNAVAILABLE),
-                    };
-                    (title, parent)
-                }
-                Err(err) => {
-                    println!("{}", err);
-                    return Err(StatusCode::SERVICE_UNAVAILABLE);
-                }
-            },
-        };
-        cache::cache_put(
-            Arc::clone(&*cache::MARVIN_PROJECT_CACHE),
-            parent_id,
-            parent.clone(),
-        );
-        parents.push(remove_timestamp_prefix(&parent.0));
-        parent_id = parent.1;
-        sleep(Duration::from_secs(2)).await;
-    }
+    log_toggl_cache_state().await;
+
+    // Parent hierarchy from the category tree, nearest first
+    let parents: Vec<String> = categories::ancestors(&payload.parent_id)
+        .await?
+        .iter()
+        .map(|item| remove_timestamp_prefix(&item.title))
+        .collect();
 
     println!("Parent hierarchy (len={}): {:?}", parents.len(), parents);
 
     // Collect tags from labels
     let mut tags: Vec<i64> = vec![];
     for id in &payload.label_ids {
-        let label = cache::cache_get(Arc::clone(&*cache::MARVIN_LABEL_CACHE), id);
-        let label = match label {
-            Some(label) => label,
-            None => {
-                let mut result: String = "".to_string();
-                sleep(Duration::from_secs(2)).await;
-                match marvin_client.get_labels().await {
-                    Ok(labels) => {
-                        for l in labels {
-                            if *id == l.id {
-                                result = l.title.to_string();
-                            }
-                            cache::cache_put(
-                                Arc::clone(&*cache::MARVIN_LABEL_CACHE),
-                                l.id,
-                                l.title,
-                            );
-                        }
-                    }
-                    Err(err) => {
-                        println!("Error collecting labels: {}", err);
-                        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                    }
-                }
-                result
-            }
-        };
+        let label = label_title(marvin_client, id).await?;
         if label.is_empty() {
             continue;
         }
-        let tag = cache::cache_get(Arc::clone(&*cache::TOGGL_TAG_CACHE), &label);
-        let tag = match tag {
+        let tag = match cache::TOGGL_TAG_CACHE.get(&label).await {
             Some(tag) => tag,
             None => {
-                let mut result: i64 = -1;
-                sleep(Duration::from_secs(2)).await;
-                match toggl_client.list_tags(workspace_id).await {
-                    Ok(existing_tags) => {
-                        for t in existing_tags {
-                            if label == t.name {
-                                result = t.id;
-                            }
-                            cache::cache_put(Arc::clone(&*cache::TOGGL_TAG_CACHE), t.name, t.id);
+                // Concurrent misses share one listing of the workspace's tags
+                IN_FLIGHT
+                    .run("toggl_tags", || async {
+                        if cache::TOGGL_TAG_CACHE.get(&label).await.is_some() {
+                            return Ok(false);
                         }
-                    }
-                    Err(err) => {
-                        println!("Error collecting tags: {}", err);
-                        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                    }
-                }
-
-                if result == -1 && create_if_missing {
-                    let tag_request = CreateTagRequest { name: label.clone() };
-                    match toggl_client.create_tag(workspace_id, &tag_request).await {
-                        Ok(tag) => {
-                            result = tag.id;
-                            cache::cache_put(Arc::clone(&*cache::TOGGL_TAG_CACHE), tag.name, tag.id);
-                        }
-                        Err(err) => {
-                            println!("Error adding tag: {}", err);
-                            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                        let existing_tags = toggl_client
+                            .list_tags(workspace_id)
+                            .await
+                            .inspect_err(|err| println!("Error collecting tags: {}", err))?;
+                        for t in existing_tags {
+                            cache::TOGGL_TAG_CACHE.put(t.name, t.id).await;
                         }
+                        Ok::<_, WebhookError>(true)
+                    })
+                    .await?;
+                match cache::TOGGL_TAG_CACHE.get(&label).await {
+                    Some(tag) => tag,
+                    None if create_if_missing => {
+                        // Only one webhook creates a given tag
+                        IN_FLIGHT
+                            .run(&format!("toggl_tag:{}", label), || async {
+                                if cache::TOGGL_TAG_CACHE.get(&label).await.is_some() {
+                                    return Ok(false);
+                                }
+                                let tag_request = CreateTagRequest { name: label.clone() };
+                                let tag = toggl_client
+                                    .create_tag(workspace_id, &tag_request)
+                                    .await
+                                    .inspect_err(|err| println!("Error adding tag: {}", err))?;
+                                cache::TOGGL_TAG_CACHE.put(tag.name, tag.id).await;
+                                Ok::<_, WebhookError>(true)
+                            })
+                            .await?;
+                        cache::TOGGL_TAG_CACHE.get(&label).await.unwrap_or(-1)
                     }
+                    None => -1,
                 }
-                result
             }
         };
         if tag != -1 {
@@ -211,38 +302,47 @@ async fn resolve_marvin_task_to_toggl(
         client_name, project_name, task_name, description
     );
 
-    // Resolve client ID
-    let client_id = match cache_get(Arc::clone(&*TOGGL_CLIENT_CACHE), &client_name) {
+    // Resolve client ID. Concurrent misses share one listing of the workspace's
+    // clients, projects or tasks, and only one webhook creates a missing one.
+    let client_id = match TOGGL_CLIENT_CACHE.get(&client_name).await {
         Some(id) => Some(id),
         None => {
-            let clients = match toggl_client.list_clients(workspace_id, None, None).await {
-                Ok(clients) => clients,
-                Err(error) => {
-                    println!("Error fetching clients {}", error);
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                }
-            };
-            let mut found_id: Option<i64> = None;
-            for c in clients {
-                if client_name == c.name {
-                    found_id = Some(c.id);
-                }
-                cache_put(Arc::clone(&*TOGGL_CLIENT_CACHE), c.name, c.id);
-            }
-            match found_id {
+            IN_FLIGHT
+                .run("toggl_clients", || async {
+                    if TOGGL_CLIENT_CACHE.get(&client_name).await.is_some() {
+                        return Ok(false);
+                    }
+                    let clients = toggl_client
+                        .list_clients(workspace_id, None, None)
+                        .await
+                        .inspect_err(|error| println!("Error fetching clients {}", error))?;
+                    for c in clients {
+                        TOGGL_CLIENT_CACHE.put(c.name, c.id).await;
+                    }
+                    Ok::<_, WebhookError>(true)
+                })
+                .await?;
+            match TOGGL_CLIENT_CACHE.get(&client_name).await {
                 Some(id) => Some(id),
                 None if create_if_missing => {
-                    let request = &CreateClientRequest {
-                        name: client_name.clone(),
-                        notes: None,
-                    };
-                    match toggl_client.create_client(workspace_id, request).await {
-                        Ok(c) => Some(c.id),
-                        Err(error) => {
-                            println!("Error creating client {}", error);
-                            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                        }
-                    }
+                    IN_FLIGHT
+                        .run(&format!("toggl_client:{}", client_name), || async {
+                            if TOGGL_CLIENT_CACHE.get(&client_name).await.is_some() {
+                                return Ok(false);
+                            }
+                            let request = &CreateClientRequest {
+                                name: client_name.clone(),
+                                notes: None,
+                            };
+                            let c = toggl_client
+                                .create_client(workspace_id, request)
+                                .await
+                                .inspect_err(|error| println!("Error creating client {}", error))?;
+                            TOGGL_CLIENT_CACHE.put(c.name, c.id).await;
+                            Ok::<_, WebhookError>(true)
+                        })
+                        .await?;
+                    TOGGL_CLIENT_CACHE.get(&client_name).await
                 }
                 None => None,
             }
@@ -252,44 +352,59 @@ async fn resolve_marvin_task_to_toggl(
     // Resolve project ID (requires client_id)
     let project_id = match client_id {
         Some(cid) => {
-            match cache_get(Arc::clone(&*TOGGL_PROJECT_CACHE), &(cid, project_name.clone())) {
+            let key = (cid, project_name.clone());
+            match TOGGL_PROJECT_CACHE.get(&key).await {
                 Some(id) => Some(id),
                 None => {
-                    let projects = match toggl_client.list_projects(workspace_id).await {
-                        Ok(projects) => projects,
-                        Err(error) => {
-                            println!("Error fetching projects {}", error);
-                            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                        }
-                    };
-                    let mut found_id: Option<i64> = None;
-                    for p in projects {
-                        if project_name == p.name && p.client_id == Some(cid) {
-                            found_id = Some(p.id);
-                        }
-                        if let Some(pcid) = p.client_id {
-                            cache_put(Arc::clone(&*TOGGL_PROJECT_CACHE), (pcid, p.name), p.id);
-                        }
-                    }
-                    match found_id {
-                        Some(id) => Some(id),
-                        None if create_if_missing => {
-                            let mut request: crate::toggl_api::requests::CreateProjectRequest =
-                                Default::default();
-                            request.active = Some(true);
-                            request.auto_estimates = Some(false);
-                            request.billable = Some(false);
-                            request.color = Some("#ffffff".to_string());
-                            request.is_private = Some(true);
-                            request.name = project_name.clone();
-                            request.client_id = Some(cid);
-                            match toggl_client.create_project(workspace_id, &request).await {
-                                Ok(p) => Some(p.id),
-                                Err(error) => {
-                                    println!("Error creating project {}", error);
-                                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                    IN_FLIGHT
+                        .run("toggl_projects", || async {
+                            if TOGGL_PROJECT_CACHE.get(&key).await.is_some() {
+                                return Ok(false);
+                            }
+                            let projects = toggl_client
+                                .list_projects(workspace_id)
+                                .await
+                                .inspect_err(|error| println!("Error fetching projects {}", error))?;
+                            for p in projects {
+                                if let Some(pcid) = p.client_id {
+                                    TOGGL_PROJECT_CACHE.put((pcid, p.name), p.id).await;
                                 }
                             }
+                            Ok::<_, WebhookError>(true)
+                        })
+                        .await?;
+                    match TOGGL_PROJECT_CACHE.get(&key).await {
+                        Some(id) => Some(id),
+                        None if create_if_missing => {
+                            IN_FLIGHT
+                                .run(&format!("toggl_project:{}:{}", cid, project_name), || async {
+                                    if TOGGL_PROJECT_CACHE.get(&key).await.is_some() {
+                                        return Ok(false);
+                                    }
+                                    let mut request: crate::toggl_api::requests::CreateProjectRequest =
+                                        Default::default();
+                                    request.active = Some(true);
+                                    request.auto_estimates = Some(false);
+                                    request.billable = Some(false);
+                                    request.color = Some("#ffffff".to_string());
+                                    request.is_private = Some(true);
+                                    request.name = project_name.clone();
+                                    request.client_id = Some(cid);
+                                    let p = match toggl_client.create_project(workspace_id, &request).await {
+                                        Ok(p) => p,
+                                        Err(error) => {
+                                            println!("Error creating project {}", error);
+                                            if error.is_stale_reference() {
+                                                evict_client(cid).await;
+                                            }
+                                            return Err(error.into());
+                                        }
+                                    };
+                                    TOGGL_PROJECT_CACHE.put((cid, p.name), p.id).await;
+                                    Ok::<_, WebhookError>(true)
+                                })
+                                .await?;
+                            TOGGL_PROJECT_CACHE.get(&key).await
                         }
                         None => None,
                     }
@@ -302,39 +417,60 @@ async fn resolve_marvin_task_to_toggl(
     // Resolve task ID (requires project_id)
     let task_id = match (project_id, &task_name) {
         (Some(pid), Some(tname)) => {
-            match cache_get(Arc::clone(&*TOGGL_TASK_CACHE), &(pid, tname.clone())) {
+            let key = (pid, tname.clone());
+            match TOGGL_TASK_CACHE.get(&key).await {
                 Some(id) => Some(id),
                 None => {
-                    let tasks = match toggl_client.get_project_tasks(workspace_id, pid).await {
-                        Ok(tasks) => tasks,
-                        Err(error) => {
-                            println!("Error fetching tasks {}", error);
-                            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                        }
-                    };
-                    let mut found_id: Option<i64> = None;
-                    for t in tasks {
-                        if *tname == t.name {
-                            found_id = Some(t.id);
-                        }
-                        cache_put(Arc::clone(&*TOGGL_TASK_CACHE), (pid, t.name), t.id);
-                    }
-                    match found_id {
-                        Some(id) => Some(id),
-                        None if create_if_missing => {
-                            let request = &crate::toggl_api::requests::CreateTaskRequest {
-                                active: Some(true),
-                                estimated_seconds: Some(0),
-                                name: tname.clone(),
-                                user_id: None,
-                            };
-                            match toggl_client.create_task(workspace_id, pid, request).await {
-                                Ok(t) => Some(t.id),
+                    IN_FLIGHT
+                        .run(&format!("toggl_tasks:{}", pid), || async {
+                            if TOGGL_TASK_CACHE.get(&key).await.is_some() {
+                                return Ok(false);
+                            }
+                            let tasks = match toggl_client.get_project_tasks(workspace_id, pid).await {
+                                Ok(tasks) => tasks,
                                 Err(error) => {
-                                    println!("Error creating task {}", error);
-                                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                                    println!("Error fetching tasks {}", error);
+                                    if error.is_stale_reference() {
+                                        evict_project(pid).await;
+                                    }
+                                    return Err(error.into());
                                 }
+                            };
+                            for t in tasks {
+                                TOGGL_TASK_CACHE.put((pid, t.name), t.id).await;
                             }
+                            Ok::<_, WebhookError>(true)
+                        })
+                        .await?;
+                    match TOGGL_TASK_CACHE.get(&key).await {
+                        Some(id) => Some(id),
+                        None if create_if_missing => {
+                            IN_FLIGHT
+                                .run(&format!("toggl_task:{}:{}", pid, tname), || async {
+                                    if TOGGL_TASK_CACHE.get(&key).await.is_some() {
+                                        return Ok(false);
+                                    }
+                                    let request = &crate::toggl_api::requests::CreateTaskRequest {
+                                        active: Some(true),
+                                        estimated_seconds: Some(0),
+                                        name: tname.clone(),
+                                        user_id: None,
+                                    };
+                                    let t = match toggl_client.create_task(workspace_id, pid, request).await {
+                                        Ok(t) => t,
+                                        Err(error) => {
+                                            println!("Error creating task {}", error);
+                                            if error.is_stale_reference() {
+                                                evict_project(pid).await;
+                                            }
+                                            return Err(error.into());
+                                        }
+                                    };
+                                    TOGGL_TASK_CACHE.put((pid, t.name), t.id).await;
+                                    Ok::<_, WebhookError>(true)
+                                })
+                                .await?;
+                            TOGGL_TASK_CACHE.get(&key).await
                         }
                         None => None,
                     }
@@ -364,78 +500,245 @@ pub fn router() -> Router {
         // Protected endpoints:
         .route("/start-tracking", post(start_tracking))
         .route("/stop-tracking", post(stop_tracking))
+        .route("/tomato-timer", post(tomato_timer))
         .route("/marvin-other", post(other_webhook))
+        .route("/marvin-add", post(category_changed))
+        .route("/marvin-edit", post(category_changed))
+        .route("/marvin-delete", post(category_deleted))
+        .route("/categories", get(category_info))
+        .route("/jobs", get(list_jobs))
+        .route("/dead-letters", get(list_dead_letters))
+        .route("/auto-stops", get(list_auto_stops))
+        .route("/estimate", get(estimate_status))
+        .route("/adherence", get(adherence_status))
+        .route("/dead-letters/{id}/replay", post(replay_dead_letter))
+        .route("/backfill", post(backfill))
         // Attach our auth layer to every route in this router.
-        .layer(middleware::from_fn(require_auth))
+        .layer(middleware::from_fn(|req, next| require_token("MARVIN_WEBHOOK_TOKEN", req, next)))
 }
 
-/// Check if the request has a valid "Authorization" header that matches
-/// the `MARVIN_WEBHOOK_TOKEN` environment variable.
-async fn require_auth(req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
-    // Load the secret token from env (once per request). If it's missing from env,
-    // return 500 — or handle this differently, e.g. panic at startup so you know immediately.
-    let token = match env::var("MARVIN_WEBHOOK_TOKEN") {
-        Ok(val) => val,
-        Err(_) => {
-            eprintln!("MARVIN_WEBHOOK_TOKEN is not set!");
+/// Persist a webhook as a job and answer straight away; the worker does the actual work.
+fn enqueue(kind: JobKind) -> Result<(StatusCode, String), StatusCode> {
+    match JOB_QUEUE.enqueue(kind) {
+        Ok(id) => Ok((StatusCode::ACCEPTED, format!("Job {} queued", id))),
+        Err(err) => {
+            eprintln!("Could not persist job: {}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Like `enqueue`, but repeated deliveries of the same Marvin event are not queued
+/// again; they get the outcome of the job that handled the first delivery instead.
+fn enqueue_once(kind: JobKind) -> Result<(StatusCode, String), StatusCode> {
+    let key = idempotency_key(&kind);
+    let (id, duplicate) = match IDEMPOTENCY.get_or_insert_with(key, || JOB_QUEUE.enqueue(kind)) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("Could not persist job: {}", err);
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
+    let id = match id {
+        Some(id) => id,
+        None => return Ok((StatusCode::ACCEPTED, "Event is already being queued".to_string())),
+    };
+    if !duplicate {
+        return Ok((StatusCode::ACCEPTED, format!("Job {} queued", id)));
+    }
 
-    // Check the Authorization header
-    eprintln!("Headers: {:#?}", req.headers());
-    let auth_header = req.headers().get("Authorization");
-    match auth_header {
-        Some(header_value) if header_value == token.as_str() => {
-            // Correct token => allow the request to continue
-            Ok(next.run(req).await)
-        }
-        _ => {
-            eprintln!("Unauthorized webhook attempt");
-            Err(StatusCode::UNAUTHORIZED)
-        }
+    match JOB_QUEUE.get(id) {
+        Some(Job { status: JobStatus::Succeeded, result: Some(result), .. }) => Ok((StatusCode::OK, result)),
+        Some(Job { status: JobStatus::Failed, last_error, .. }) => Ok((
+            StatusCode::OK,
+            format!("Job {} failed: {}", id, last_error.unwrap_or_default()),
+        )),
+        _ => Ok((StatusCode::ACCEPTED, format!("Job {} queued", id))),
     }
 }
 
-/// Primary endpoint that routes based on `webhook_type`.
-async fn start_tracking(Json(payload): Json<Task>) -> Result<String, StatusCode> {
+/// POST /start-tracking
+async fn start_tracking(Json(payload): Json<Task>) -> Result<(StatusCode, String), StatusCode> {
     println!("Webhook Called");
+    enqueue_once(JobKind::StartTracking(payload))
+}
 
-    let marvin_api_token = match env::var("MARVIN_API_TOKEN") {
-        Ok(val) => val,
-        Err(_) => {
-            eprintln!("MARVIN_API_TOKEN is not set!");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+/// POST /stop-tracking
+async fn stop_tracking(Json(payload): Json<Task>) -> Result<(StatusCode, String), StatusCode> {
+    println!("Webhook Called");
+    enqueue_once(JobKind::StopTracking(payload))
+}
 
-    let marvin_full_access_token = match env::var("MARVIN_FULL_ACCESS_TOKEN") {
-        Ok(val) => val,
-        Err(_) => {
-            eprintln!("MARVIN_FULL_ACCESS_TOKEN is not set!");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+/// POST /tomato-timer
+/// Marvin's pomodoro timer, sent whenever it moves between work and break.
+async fn tomato_timer(Json(payload): Json<TomatoTimer>) -> Result<(StatusCode, String), StatusCode> {
+    println!("Webhook Called");
+    enqueue_once(JobKind::TomatoTimer(payload))
+}
+
+/// GET /jobs
+/// Status of queued, running and recently finished webhook jobs.
+async fn list_jobs() -> Json<Vec<Job>> {
+    Json(JOB_QUEUE.list())
+}
+
+/// GET /dead-letters
+/// Webhooks that failed for good, with the errors that made them fail.
+async fn list_dead_letters() -> Json<Vec<DeadLetter>> {
+    Json(DEAD_LETTERS.list())
+}
+
+/// GET /auto-stops
+/// Entries the watchdog stopped because they were left running.
+async fn list_auto_stops() -> Json<Vec<AutoStop>> {
+    Json(AUTO_STOPS.list())
+}
+
+/// GET /estimate
+/// The running Marvin task's tracked time against its estimate, if it has one.
+async fn estimate_status() -> Json<Option<EstimateStatus>> {
+    Json(estimate::status())
+}
+
+#[derive(Deserialize)]
+struct AdherenceQuery {
+    /// YYYY-MM-DD, today if omitted
+    date: Option<NaiveDate>,
+}
+
+/// GET /adherence?date=YYYY-MM-DD
+/// Planned versus tracked minutes for each of the day's time blocks.
+async fn adherence_status(Query(query): Query<AdherenceQuery>) -> Result<Json<AdherenceReport>, StatusCode> {
+    let date = query.date.unwrap_or_else(|| days::day_of(Utc::now()));
+    match adherence::adherence_report(date).await {
+        Ok(report) => Ok(Json(report)),
+        Err(err) => {
+            println!("Adherence report failed: {}", err.chain().join(": "));
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
+    }
+}
+
+/// POST /dead-letters/{id}/replay
+/// Queue a failed webhook again, e.g. after fixing whatever made it fail.
+async fn replay_dead_letter(Path(id): Path<u64>) -> Result<(StatusCode, String), StatusCode> {
+    let letter = match DEAD_LETTERS.get(id) {
+        Some(letter) => letter,
+        None => return Err(StatusCode::NOT_FOUND),
     };
+    let response = enqueue(letter.kind)?;
+    DEAD_LETTERS.remove(id);
+    println!("Replayed dead letter {}", id);
+    Ok(response)
+}
 
-    let toggl_api_token = match env::var("TOGGL_API_TOKEN") {
-        Ok(val) => val,
-        Err(_) => {
-            eprintln!("TOGGL_API_TOKEN is not set!");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+/// POST /backfill
+/// Create Toggl entries for sessions Marvin tracked while we were down.
+/// Dry runs and real runs are both queued; the report ends up as the job's result.
+async fn backfill(Json(request): Json<BackfillRequest>) -> Result<Response, StatusCode> {
+    if let Err(message) = request.validate() {
+        println!("Invalid backfill request: {}", message);
+        return Ok((StatusCode::BAD_REQUEST, message).into_response());
+    }
+
+    enqueue_once(JobKind::Backfill(request)).map(IntoResponse::into_response)
+}
+
+/// POST /marvin-add and /marvin-edit
+/// Keep the category tree current when categories or projects are added or changed,
+/// and the label cache when labels are.
+async fn category_changed(Json(payload): Json<Value>) -> Result<String, StatusCode> {
+    let is_category = categories::is_category_doc(&payload);
+    let mut doc: MarvinDoc = match serde_json::from_value(payload) {
+        Ok(doc) => doc,
+        Err(err) => {
+            println!("Invalid Marvin doc: {}", err);
+            return Err(StatusCode::BAD_REQUEST);
         }
     };
+    // Some payloads only say what they are through `type`
+    if doc.db.is_none() && is_category {
+        doc.db = Some(ProjectOrCategory::DB.to_string());
+    }
+    if !matches!(doc.db.as_deref(), Some(ProjectOrCategory::DB | Label::DB)) {
+        return Ok("Not a category, project or label, ignored".to_string());
+    }
+    match TypedDoc::from_doc(doc).map(|versioned| versioned.doc) {
+        Ok(TypedDoc::Category(item)) => {
+            categories::upsert(item);
+            Ok("Category tree updated".to_string())
+        }
+        Ok(TypedDoc::Label(label)) => {
+            cache::MARVIN_LABEL_CACHE.put(label.id, label.title).await;
+            Ok("Label cache updated".to_string())
+        }
+        Ok(other) => Ok(format!("Doc {} is not a category, project or label, ignored", other.id())),
+        Err(err) => {
+            println!("Invalid category, project or label: {}", err);
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
 
-    let workspace_id = match WORKSPACE_ID.get() {
-        Some(workspace_id) => *workspace_id,
-        None => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+/// POST /marvin-delete
+/// Forget deleted categories, projects and labels.
+async fn category_deleted(Json(payload): Json<Value>) -> Result<String, StatusCode> {
+    let id = match payload.get("_id") {
+        Some(Value::String(id)) => id,
+        _ => return Err(StatusCode::BAD_REQUEST),
     };
+    if categories::remove(id).is_some() {
+        return Ok("Category tree updated".to_string());
+    }
+    if cache::MARVIN_LABEL_CACHE.remove(id).await.is_some() {
+        return Ok("Label cache updated".to_string());
+    }
+    Ok("Not a known category, project or label, ignored".to_string())
+}
 
-    let toggl_client = TogglClient::new(toggl_api_token, "api_token".to_string());
-    let marvin_client = MarvinClient::new(Some(marvin_api_token), Some(marvin_full_access_token));
+#[derive(Debug, Deserialize)]
+struct CategoryQuery {
+    id: Option<String>,
+    /// Titles from the top level down, separated by '/'
+    path: Option<String>,
+}
+
+/// GET /categories?id=... or ?path=Work/Project
+async fn category_info(Query(query): Query<CategoryQuery>) -> Result<Json<CategoryInfo>, StatusCode> {
+    if query.id.is_none() && query.path.is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    match categories::lookup(query.id.as_deref(), query.path.as_deref()).await {
+        Ok(Some(info)) => Ok(Json(info)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            println!("Category lookup failed: {}", err.chain().join(": "));
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Start tracking `payload` in Toggl, stopping whatever else is running.
+/// Called by the job worker for queued `/start-tracking` webhooks.
+pub async fn process_start_tracking(payload: &Task) -> Result<String, WebhookError> {
+    if origin::take_marvin_echo(&payload.id, TrackAction::Start) {
+        println!("Start was triggered by reverse sync, nothing to do");
+        return Ok("Started from Toggl".to_string());
+    }
+
+    // Restarted right after a stop: continue the previous entry
+    if let Some(entry) = resume::try_resume(&payload.id).await? {
+        estimate::watch_entry(payload, entry.id);
+        return Ok(format!("Resumed entry {}", entry.id));
+    }
+
+    let workspace_id = workspace_id()?;
+    let toggl_client = toggl_client_from_env()?;
+    let marvin_client = marvin_client_from_env()?;
 
     // Resolve task to Toggl IDs, creating missing entities
-    let resolved = resolve_marvin_task_to_toggl(
-        &payload,
+    let mut resolved = resolve_marvin_task_to_toggl(
+        payload,
         &marvin_client,
         &toggl_client,
         workspace_id,
@@ -465,65 +768,72 @@ async fn start_tracking(Json(payload): Json<Task>) -> Result<String, StatusCode>
     }
 
     // Start new time entry
-    match toggl_client
+    let mut result = toggl_client
         .start_time_entry(
             workspace_id,
             resolved.project_id,
             resolved.task_id,
             &resolved.description,
-            resolved.tags,
+            resolved.tags.clone(),
         )
-        .await
+        .await;
+    // The caches can still hold IDs of projects or tags deleted in Toggl:
+    // forget them, resolve again and retry once
+    if let Err(error) = &result
+        && error.is_stale_reference()
     {
+        println!("Toggl rejected cached IDs ({}), resolving again", error);
+        evict_resolved(&resolved).await;
+        resolved = resolve_marvin_task_to_toggl(
+            payload,
+            &marvin_client,
+            &toggl_client,
+            workspace_id,
+            true, // create_if_missing
+        )
+        .await?;
+        result = toggl_client
+            .start_time_entry(
+                workspace_id,
+                resolved.project_id,
+                resolved.task_id,
+                &resolved.description,
+                resolved.tags,
+            )
+            .await;
+    }
+    match result {
         Err(error) => {
             println!("Start time entry error: {}", error);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(error.into());
+        }
+        Ok(entry) => {
+            origin::mark_own_entry(entry.id);
+            estimate::watch_entry(payload, entry.id);
+            if let Err(err) = adherence::check_entry(payload, &entry).await {
+                println!("Time block check failed: {}", err.chain().join(": "));
+            }
         }
-        Ok(_) => (),
     }
 
     Ok("Webhook processed successfully".to_string())
 }
 
-/// Primary endpoint that routes based on `webhook_type`.
-async fn stop_tracking(Json(payload): Json<Task>) -> Result<String, StatusCode> {
-    println!("Webhook Called");
-
-    let marvin_api_token = match env::var("MARVIN_API_TOKEN") {
-        Ok(val) => val,
-        Err(_) => {
-            eprintln!("MARVIN_API_TOKEN is not set!");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    let marvin_full_access_token = match env::var("MARVIN_FULL_ACCESS_TOKEN") {
-        Ok(val) => val,
-        Err(_) => {
-            eprintln!("MARVIN_FULL_ACCESS_TOKEN is not set!");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    let toggl_api_token = match env::var("TOGGL_API_TOKEN") {
-        Ok(val) => val,
-        Err(_) => {
-            eprintln!("TOGGL_API_TOKEN is not set!");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    let workspace_id = match WORKSPACE_ID.get() {
-        Some(workspace_id) => *workspace_id,
-        None => return Err(StatusCode::INTERNAL_SERVER_ERROR),
-    };
+/// Stop the Toggl entry for `payload` if it's the one currently running.
+/// Called by the job worker for queued `/stop-tracking` webhooks.
+pub async fn process_stop_tracking(payload: &Task) -> Result<String, WebhookError> {
+    if origin::take_marvin_echo(&payload.id, TrackAction::Stop) {
+        println!("Stop was triggered by reverse sync, nothing to do");
+        return Ok("Stopped from Toggl".to_string());
+    }
 
-    let toggl_client = TogglClient::new(toggl_api_token, "api_token".to_string());
-    let marvin_client = MarvinClient::new(Some(marvin_api_token), Some(marvin_full_access_token));
+    let workspace_id = workspace_id()?;
+    let toggl_client = toggl_client_from_env()?;
+    let marvin_client = marvin_client_from_env()?;
 
     // Resolve task to Toggl IDs (without creating missing entities)
     let resolved = resolve_marvin_task_to_toggl(
-        &payload,
+        payload,
         &marvin_client,
         &toggl_client,
         workspace_id,
@@ -534,33 +844,7 @@ async fn stop_tracking(Json(payload): Json<Task>) -> Result<String, StatusCode>
     // Collect labels for productivity override
     let mut labels: Vec<String> = vec![];
     for id in &payload.label_ids {
-        let label = cache::cache_get(Arc::clone(&*cache::MARVIN_LABEL_CACHE), id);
-        let label = match label {
-            Some(label) => label,
-            None => {
-                let mut result: String = "".to_string();
-                sleep(Duration::from_secs(2)).await;
-                match marvin_client.get_labels().await {
-                    Ok(fetched_labels) => {
-                        for l in fetched_labels {
-                            if *id == l.id {
-                                result = l.title.to_string();
-                            }
-                            cache::cache_put(
-                                Arc::clone(&*cache::MARVIN_LABEL_CACHE),
-                                l.id,
-                                l.title,
-                            );
-                        }
-                    }
-                    Err(err) => {
-                        println!("Error collecting labels: {}", err);
-                        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                    }
-                }
-                result
-            }
-        };
+        let label = label_title(&marvin_client, id).await?;
         if !label.is_empty() {
             labels.push(label);
         }
@@ -590,14 +874,16 @@ async fn stop_tracking(Json(payload): Json<Task>) -> Result<String, StatusCode>
     match result {
         Err(error) => {
             println!("Stop current time entry error: {}", error);
-            return Ok("Error stopping time entry".to_string());
+            return Err(error.into());
         }
         Ok(None) => {
             println!("No matching time entry to stop");
             return Ok("No matching time entry".to_string());
         }
-        Ok(Some(_)) => {
+        Ok(Some(entry)) => {
             println!("Time entry stopped successfully");
+            estimate::clear_watch(&payload.id);
+            resume::record_stop(&payload.id, &entry, productivity_override);
         }
     }
 
@@ -1,2 +1,6 @@
+pub mod admin;
+pub mod auth;
+pub mod heartbeat;
 pub mod marvin_webhooks;
 pub mod third_time;
+pub mod toggl_webhooks;
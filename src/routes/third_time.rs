@@ -1,12 +1,13 @@
 use axum::{
-    body::Body, http::{Request, StatusCode}, middleware::{self, Next}, response::Response, routing::{get, post}, Json, Router
+    http::StatusCode, middleware, routing::{get, post}, Json, Router
 };
 use serde::Deserialize;
 use serde_json::Value;
 use tokio::time::{sleep, Sleep};
-use std::{env, sync::{atomic::Ordering, Arc}, time::Duration};
+use chrono::NaiveDate;
+use std::{collections::BTreeMap, env, sync::{atomic::Ordering, Arc}, time::Duration};
 
-use crate::{api::{client::MarvinClient, requests::{CreateProjectRequest, CreateTaskRequest}}, cache::cache::{self, cache_get, cache_put, TOGGL_CLIENT_CACHE, TOGGL_PROJECT_CACHE, TOGGL_TASK_CACHE}, models::tasks::{ProjectOrCategory, Task}, toggl_api::{client::{TogglClient, StopCondition}, requests::CreateClientRequest}, LEISURE_BALANCE, LEISURE_RATE, WORKSPACE_ID};
+use crate::{api::{client::MarvinClient, requests::{CreateProjectRequest, CreateTaskRequest}}, cache::cache::{self, TOGGL_CLIENT_CACHE, TOGGL_PROJECT_CACHE, TOGGL_TASK_CACHE}, models::tasks::{ProjectOrCategory, Task}, routes::auth::require_token, toggl_api::{client::{TogglClient, StopCondition}, requests::CreateClientRequest}, tracking::{leisure, lock::TRACKING_LOCK}, LEISURE_BALANCE, LEISURE_RATE, WORKSPACE_ID};
 
 /// Main router for webhooks
 pub fn router() -> Router {
@@ -17,39 +18,11 @@ pub fn router() -> Router {
         .route("/change-rate", post(change_rate))
         .route("/get-balance", get(get_balance))
         .route("/get-rate", get(get_rate))
+        .route("/leisure-by-day", get(leisure_by_day))
         .route("/stop-current", get(stop_current))
-        .layer(middleware::from_fn(require_auth))
+        .layer(middleware::from_fn(|req, next| require_token("THIRD_TIME_WEBHOOK_TOKEN", req, next)))
 }
 
-/// Check if the request has a valid "Authorization" header that matches
-/// the `THIRD_TIME_WEBHOOK_TOKEN` environment variable.
-async fn require_auth(req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
-    // Load the secret token from env (once per request). If it's missing from env, 
-    // return 500 — or handle this differently, e.g. panic at startup so you know immediately.
-    let token = match env::var("THIRD_TIME_WEBHOOK_TOKEN") {
-        Ok(val) => val,
-        Err(_) => {
-            eprintln!("THIRD_TIME_WEBHOOK_TOKEN is not set!");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    // Check the Authorization header
-    eprintln!("Headers: {:#?}", req.headers());
-    let auth_header = req.headers().get("Authorization");
-    match auth_header {
-        Some(header_value) if header_value == token.as_str() => {
-            // Correct token => allow the request to continue
-            Ok(next.run(req).await)
-        }
-        _ => {
-            eprintln!("Unauthorized webhook attempt");
-            Err(StatusCode::UNAUTHORIZED)
-        }
-    }
-}
-
-
 // --------------------
 // 2. Request payloads
 // --------------------
@@ -105,6 +78,11 @@ async fn get_rate() -> Result<String, StatusCode> {
     Ok(rate.to_string())
 }
 
+// GET /leisure-by-day
+async fn leisure_by_day() -> Json<BTreeMap<NaiveDate, i64>> {
+    Json(leisure::leisure_by_day())
+}
+
 // GET /get-rate
 async fn stop_current() -> Result<String, StatusCode> {
     let toggl_api_token = match env::var("TOGGL_API_TOKEN") {
@@ -117,6 +95,7 @@ async fn stop_current() -> Result<String, StatusCode> {
 
     let toggl_client = TogglClient::new(toggl_api_token, "api_token".to_string());
 
+    let _guard = TRACKING_LOCK.lock().await;
     let result = toggl_client
         .stop_current_time_entry(None, StopCondition::Always)
         .await;
@@ -0,0 +1,165 @@
+use axum::{
+    Json, Router,
+    body::Bytes,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use std::env;
+
+use crate::{
+    cache::cache::{TOGGL_PROJECT_CACHE, TOGGL_TAG_CACHE, TOGGL_TASK_CACHE},
+    routes::marvin_webhooks::toggl_client_from_env,
+    sync::{origin, reverse::sync_running_entry},
+    toggl_api::responses::{Tag, TimeEntry, TogglProject, TogglWebhookEvent, TogglWebhookPayload},
+    tracking::{leisure::accrue_leisure_between, lock::TRACKING_LOCK},
+};
+
+/// Header carrying the HMAC of the request body: "sha256=<hex digest>"
+const SIGNATURE_HEADER: &str = "X-Webhook-Signature-256";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Router for Toggl Track webhook deliveries.
+/// These are authenticated by their signature rather than an Authorization header.
+pub fn router() -> Router {
+    Router::new().route("/toggl-webhook", post(toggl_webhook))
+}
+
+/// Check the body against the signature Toggl computed with the subscription's secret.
+fn verify_signature(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let signature = match headers.get(SIGNATURE_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let digest = match signature.strip_prefix("sha256=").map(hex::decode) {
+        Some(Ok(digest)) => digest,
+        _ => return false,
+    };
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    mac.verify_slice(&digest).is_ok()
+}
+
+/// POST /toggl-webhook
+async fn toggl_webhook(headers: HeaderMap, body: Bytes) -> Result<Response, StatusCode> {
+    let secret = match env::var("TOGGL_WEBHOOK_SECRET") {
+        Ok(val) => val,
+        Err(_) => {
+            eprintln!("TOGGL_WEBHOOK_SECRET is not set!");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    if !verify_signature(&secret, &headers, &body) {
+        eprintln!("Toggl webhook with invalid signature");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let event: TogglWebhookEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(err) => {
+            println!("Could not parse Toggl webhook: {}", err);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    // Subscription validation handshake: echo the code back
+    if let Some(code) = &event.validation_code {
+        println!("[TOGGL WEBHOOK] Validating subscription {:?}", event.subscription_id);
+        return Ok(Json(json!({ "validation_code": code })).into_response());
+    }
+
+    // Answer quickly; handling may involve several API calls
+    tokio::spawn(handle_event(event));
+    Ok("OK".into_response())
+}
+
+async fn handle_event(event: TogglWebhookEvent) {
+    let action = match &event.metadata {
+        Some(metadata) => metadata.action.clone(),
+        None => return,
+    };
+    println!("[TOGGL WEBHOOK] Event {:?}: {}", event.event_id, action);
+
+    match event.typed_payload() {
+        TogglWebhookPayload::TimeEntry(entry) => handle_time_entry(&action, entry).await,
+        TogglWebhookPayload::Project(project) => handle_project(&action, project).await,
+        TogglWebhookPayload::Tag(tag) => handle_tag(&action, tag).await,
+        TogglWebhookPayload::Other(payload) => println!("[TOGGL WEBHOOK] Ignoring payload {}", payload),
+    }
+}
+
+/// Account for entries stopped outside marvinhooks and keep reverse sync up to date.
+/// Only the stop of an entry seen running counts: entries created already stopped
+/// (by hand, backfills, midnight splits) and edits of old entries leave leisure alone.
+async fn handle_time_entry(action: &str, entry: TimeEntry) {
+    if action == "deleted" {
+        return;
+    }
+
+    let _guard = TRACKING_LOCK.lock().await;
+
+    let stopped = match &entry.stop {
+        None => {
+            origin::mark_running(entry.id);
+            false
+        }
+        Some(stop) if origin::take_running(entry.id) => {
+            let start: Option<DateTime<Utc>> = entry.start.parse().ok();
+            let stop: Option<DateTime<Utc>> = stop.parse().ok();
+            if let (Some(start), Some(stop)) = (start, stop) {
+                println!("[TOGGL WEBHOOK] Entry {} was stopped outside marvinhooks", entry.id);
+                accrue_leisure_between(entry.tags.as_deref(), None, start, stop);
+            }
+            true
+        }
+        Some(_) => false,
+    };
+
+    let toggl_client = match toggl_client_from_env() {
+        Ok(client) => client,
+        Err(err) => {
+            println!("[TOGGL WEBHOOK] {}", err);
+            return;
+        }
+    };
+    if stopped
+        && let Err(err) = toggl_client.split_at_midnight(&entry).await
+    {
+        println!("[TOGGL WEBHOOK] Could not split entry {}: {}", entry.id, err);
+    }
+    match toggl_client.get_current_time_entry().await {
+        Ok(current) => {
+            if let Err(err) = sync_running_entry(current).await {
+                println!("[TOGGL WEBHOOK] Reverse sync failed: {}", err.chain().join(": "));
+            }
+        }
+        Err(err) => println!("[TOGGL WEBHOOK] Could not fetch current entry: {}", err),
+    }
+}
+
+async fn handle_project(action: &str, project: TogglProject) {
+    // Drop the old name (renames) and, for deletions, the project's tasks
+    TOGGL_PROJECT_CACHE.remove_where(|_, id| *id == project.id).await;
+    if action == "deleted" {
+        TOGGL_TASK_CACHE
+            .remove_where(|(project_id, _), _| *project_id == project.id)
+            .await;
+        return;
+    }
+    if let Some(client_id) = project.client_id {
+        TOGGL_PROJECT_CACHE.put((client_id, project.name), project.id).await;
+    }
+}
+
+async fn handle_tag(action: &str, tag: Tag) {
+    TOGGL_TAG_CACHE.remove_where(|_, id| *id == tag.id).await;
+    if action != "deleted" {
+        TOGGL_TAG_CACHE.put(tag.name, tag.id).await;
+    }
+}
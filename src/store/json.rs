@@ -0,0 +1,85 @@
+use serde::{Serialize, de::DeserializeOwned};
+use std::{env, fs, io, path::PathBuf};
+
+/// Directory where marvinhooks keeps state that has to survive a restart.
+/// Set `MARVINHOOKS_DATA_DIR` to override; defaults to `./data`.
+pub fn data_dir() -> PathBuf {
+    match env::var("MARVINHOOKS_DATA_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from("data"),
+    }
+}
+
+/// Load a JSON document from the data directory.
+/// Returns None if the file doesn't exist yet or can't be parsed.
+pub fn load_json<T>(name: &str) -> Option<T>
+where
+    T: DeserializeOwned,
+{
+    let path = data_dir().join(name);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            eprintln!("[STORE] Could not read {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    match serde_json::from_str(&text) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            eprintln!("[STORE] Could not parse {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Like `load_json`, but for state that must not be lost: a file that can't be read
+/// or parsed is moved aside to `<name>.corrupt-<timestamp>` before None is returned,
+/// so the next save doesn't overwrite it. Panics if it can't be moved aside.
+pub fn load_json_or_set_aside<T>(name: &str) -> Option<T>
+where
+    T: DeserializeOwned,
+{
+    let path = data_dir().join(name);
+    let error = match fs::read_to_string(&path) {
+        Ok(text) => match serde_json::from_str(&text) {
+            Ok(value) => return Some(value),
+            Err(err) => err.to_string(),
+        },
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => err.to_string(),
+    };
+    let aside = data_dir().join(format!("{}.corrupt-{}", name, chrono::Utc::now().timestamp()));
+    eprintln!(
+        "[STORE] Could not load {}: {}. Moving it to {} and starting empty",
+        path.display(),
+        error,
+        aside.display()
+    );
+    if let Err(err) = fs::rename(&path, &aside) {
+        panic!("Could not move {} aside: {}", path.display(), err);
+    }
+    None
+}
+
+/// Write a JSON document to the data directory.
+/// The document is written to a temporary file first and then renamed into place,
+/// so a crash mid-write never leaves a truncated file behind.
+pub fn save_json<T>(name: &str, value: &T) -> io::Result<()>
+where
+    T: Serialize,
+{
+    let text = serde_json::to_string_pretty(value).map_err(io::Error::other)?;
+    save_text(name, &text)
+}
+
+/// Write an already serialized JSON document to the data directory, like `save_json`.
+pub fn save_text(name: &str, text: &str) -> io::Result<()> {
+    let dir = data_dir();
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(name);
+    let tmp_path = dir.join(format!("{}.tmp", name));
+    fs::write(&tmp_path, text)?;
+    fs::rename(&tmp_path, &path)
+}
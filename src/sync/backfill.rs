@@ -0,0 +1,328 @@
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    api::{client::MarvinClient, requests::TracksRequest},
+    jobs::error::WebhookError,
+    models::tasks::Task,
+    routes::marvin_webhooks::{
+        ResolvedTogglIds, evict_resolved, marvin_client_from_env, resolve_marvin_task_to_toggl,
+        toggl_client_from_env, workspace_id,
+    },
+    toggl_api::{client::TogglClient, requests::CreateTimeEntryRequest, responses::TimeEntry},
+    tracking::lock::TRACKING_LOCK,
+};
+
+/// Longest date range a single backfill may cover
+const MAX_BACKFILL_DAYS: i64 = 31;
+
+/// POST /backfill body. Dates are YYYY-MM-DD (UTC) and inclusive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackfillRequest {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    /// Only report what would be created
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl BackfillRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.end_date < self.start_date {
+            return Err("end_date is before start_date".to_string());
+        }
+        if (self.end_date - self.start_date).num_days() >= MAX_BACKFILL_DAYS {
+            return Err(format!("Backfill covers at most {} days", MAX_BACKFILL_DAYS));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionAction {
+    /// Toggl already has an entry covering this session
+    Exists,
+    /// Missing from Toggl; would be created (dry run)
+    Missing,
+    Created,
+    Failed,
+}
+
+/// One tracked session from Marvin and what the backfill did about it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackfillSession {
+    pub task_id: String,
+    pub title: String,
+    pub start: String,
+    pub stop: String,
+    pub duration_secs: i64,
+    pub action: SessionAction,
+    #[serde(default)]
+    pub toggl_entry_id: Option<i64>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackfillReport {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub dry_run: bool,
+    pub sessions: Vec<BackfillSession>,
+    pub existing: usize,
+    pub missing: usize,
+    pub created: usize,
+    pub failed: usize,
+}
+
+fn to_utc(ms: i64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_millis(ms)
+}
+
+/// The [start, stop) span of a Toggl entry; running entries end now.
+fn entry_span(entry: &TimeEntry) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let start: DateTime<Utc> = entry.start.parse().ok()?;
+    let stop = match &entry.stop {
+        Some(stop) => stop.parse().ok()?,
+        None => Utc::now(),
+    };
+    Some((start, stop))
+}
+
+/// A session counts as tracked if some Toggl entry overlaps at least half of it.
+fn is_covered(start: DateTime<Utc>, stop: DateTime<Utc>, spans: &[(DateTime<Utc>, DateTime<Utc>)]) -> bool {
+    let length = (stop - start).num_milliseconds();
+    spans.iter().any(|(entry_start, entry_stop)| {
+        let overlap = (*entry_stop.min(&stop) - *entry_start.max(&start)).num_milliseconds();
+        overlap > 0 && overlap * 2 >= length
+    })
+}
+
+/// Compare the sessions Marvin tracked between `start_date` and `end_date` with
+/// the entries in Toggl, and create completed Toggl entries for the ones that are
+/// missing (unless this is a dry run). Sessions that can't be created are reported
+/// as failed; the run carries on with the rest. The tracking lock is only held
+/// while an entry is created, so webhooks aren't held up by a long backfill.
+pub async fn run_backfill(request: &BackfillRequest) -> Result<BackfillReport, WebhookError> {
+    request.validate().map_err(WebhookError::DataError)?;
+
+    let workspace_id = workspace_id()?;
+    let toggl_client = toggl_client_from_env()?;
+    let marvin_client = marvin_client_from_env()?;
+
+    println!(
+        "[BACKFILL] {} to {} (dry run: {})",
+        request.start_date, request.end_date, request.dry_run
+    );
+
+    // Collect everything Marvin had scheduled or completed in the range
+    let mut tasks: Vec<Task> = vec![];
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut date = request.start_date;
+    while date <= request.end_date {
+        let day = date.format("%Y-%m-%d").to_string();
+        let today_items = marvin_client.get_today_items(Some(&day)).await?;
+        let done_items = marvin_client.get_done_items(Some(&day)).await?;
+        for task in today_items.into_iter().chain(done_items) {
+            if seen.insert(task.id.clone()) {
+                tasks.push(task);
+            }
+        }
+        date += ChronoDuration::days(1);
+    }
+
+    // Items without times in the listing: ask the tracks endpoint
+    let untracked: Vec<String> = tasks
+        .iter()
+        .filter(|task| task.times.is_empty())
+        .map(|task| task.id.clone())
+        .collect();
+    if !untracked.is_empty() {
+        let tracks = marvin_client
+            .get_tracks(&TracksRequest { task_ids: untracked })
+            .await?;
+        let tracks: HashMap<String, Vec<i64>> = tracks
+            .into_iter()
+            .map(|track| (track.task_id, track.times))
+            .collect();
+        for task in tasks.iter_mut().filter(|task| task.times.is_empty()) {
+            if let Some(times) = tracks.get(&task.id) {
+                task.times = times.clone();
+            }
+        }
+    }
+
+    // Existing Toggl entries, with a day of slack on either side
+    let range_start = request.start_date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let range_end = (request.end_date + ChronoDuration::days(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    let entries = toggl_client
+        .list_time_entries(
+            &(request.start_date - ChronoDuration::days(1)).format("%Y-%m-%d").to_string(),
+            &(request.end_date + ChronoDuration::days(2)).format("%Y-%m-%d").to_string(),
+        )
+        .await?;
+    let spans: Vec<_> = entries.iter().filter_map(entry_span).collect();
+
+    let mut sessions: Vec<BackfillSession> = vec![];
+    let mut resolved_by_task: HashMap<String, ResolvedTogglIds> = HashMap::new();
+
+    for task in &tasks {
+        // times is [start, stop, start, stop, ...]; a trailing start is still running
+        for pair in task.times.chunks_exact(2) {
+            let (start, stop) = match (to_utc(pair[0]), to_utc(pair[1])) {
+                (Some(start), Some(stop)) if stop > start => (start, stop),
+                _ => continue,
+            };
+            if start < range_start || start >= range_end {
+                continue;
+            }
+
+            let mut session = BackfillSession {
+                task_id: task.id.clone(),
+                title: task.title.clone(),
+                start: start.to_rfc3339(),
+                stop: stop.to_rfc3339(),
+                duration_secs: (stop - start).num_seconds(),
+                action: SessionAction::Exists,
+                toggl_entry_id: None,
+                error: None,
+            };
+
+            if is_covered(start, stop, &spans) {
+                sessions.push(session);
+                continue;
+            }
+            session.action = SessionAction::Missing;
+            if request.dry_run {
+                sessions.push(session);
+                continue;
+            }
+
+            let created = create_entry(
+                task,
+                &session,
+                &mut resolved_by_task,
+                &marvin_client,
+                &toggl_client,
+                workspace_id,
+            )
+            .await;
+            match created {
+                Ok(entry) => {
+                    println!("[BACKFILL] Created entry {} for '{}'", entry.id, task.title);
+                    session.action = SessionAction::Created;
+                    session.toggl_entry_id = Some(entry.id);
+                }
+                Err(err) => {
+                    let error = err.chain().join(": ");
+                    println!("[BACKFILL] Could not create entry for '{}': {}", task.title, error);
+                    session.action = SessionAction::Failed;
+                    session.error = Some(error);
+                }
+            }
+            sessions.push(session);
+        }
+    }
+
+    sessions.sort_by(|a, b| a.start.cmp(&b.start));
+    let count = |action: SessionAction| sessions.iter().filter(|s| s.action == action).count();
+    let report = BackfillReport {
+        start_date: request.start_date,
+        end_date: request.end_date,
+        dry_run: request.dry_run,
+        existing: count(SessionAction::Exists),
+        missing: count(SessionAction::Missing),
+        created: count(SessionAction::Created),
+        failed: count(SessionAction::Failed),
+        sessions,
+    };
+    println!(
+        "[BACKFILL] Done: {} existing, {} missing, {} created, {} failed",
+        report.existing, report.missing, report.created, report.failed
+    );
+    Ok(report)
+}
+
+/// Create the Toggl entry for a missing `session` of `task`, resolving the task's
+/// Toggl IDs first (once per task).
+async fn create_entry(
+    task: &Task,
+    session: &BackfillSession,
+    resolved_by_task: &mut HashMap<String, ResolvedTogglIds>,
+    marvin_client: &MarvinClient,
+    toggl_client: &TogglClient,
+    workspace_id: i64,
+) -> Result<TimeEntry, WebhookError> {
+    let resolved = match resolved_by_task.get(&task.id) {
+        Some(resolved) => resolved.clone(),
+        None => {
+            let resolved = resolve_marvin_task_to_toggl(
+                task,
+                marvin_client,
+                toggl_client,
+                workspace_id,
+                true, // create_if_missing
+            )
+            .await?;
+            resolved_by_task.insert(task.id.clone(), resolved.clone());
+            resolved
+        }
+    };
+
+    let _guard = TRACKING_LOCK.lock().await;
+    let body = entry_request(session, &resolved, workspace_id);
+    let result = toggl_client.create_time_entry(workspace_id, &body).await;
+    // Cached IDs of projects or tags deleted in Toggl: resolve again and retry once
+    match result {
+        Err(err) if err.is_stale_reference() => {
+            println!("[BACKFILL] Toggl rejected cached IDs ({}), resolving again", err);
+            evict_resolved(&resolved).await;
+            let resolved = resolve_marvin_task_to_toggl(
+                task,
+                marvin_client,
+                toggl_client,
+                workspace_id,
+                true, // create_if_missing
+            )
+            .await?;
+            resolved_by_task.insert(task.id.clone(), resolved.clone());
+            let body = entry_request(session, &resolved, workspace_id);
+            Ok(toggl_client.create_time_entry(workspace_id, &body).await?)
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Request creating a stopped entry for `session` with the Toggl IDs in `resolved`.
+fn entry_request(
+    session: &BackfillSession,
+    resolved: &ResolvedTogglIds,
+    workspace_id: i64,
+) -> CreateTimeEntryRequest {
+    CreateTimeEntryRequest {
+        billable: Some(false),
+        created_with: "MarvinWebhook".to_string(),
+        description: Some(resolved.description.clone()),
+        duration: session.duration_secs,
+        duronly: None,
+        event_metadata: None,
+        pid: None,
+        project_id: resolved.project_id,
+        shared_with_user_ids: None,
+        start: session.start.clone(),
+        start_date: None,
+        stop: Some(session.stop.clone()),
+        tag_action: Some("add".to_string()),
+        tag_ids: Some(resolved.tags.clone()),
+        tags: None,
+        task_id: resolved.task_id,
+        tid: None,
+        user_id: None,
+        workspace_id,
+    }
+}
@@ -0,0 +1,3 @@
+pub mod backfill;
+pub mod origin;
+pub mod reverse;
@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::store::json;
+
+/// How long we remember that a change came from marvinhooks itself (ms)
+const ECHO_WINDOW_MS: i64 = 1000 * 60 * 10;
+/// File (inside the data directory) the entries marvinhooks started are persisted to,
+/// so reverse sync still leaves them alone after a restart.
+const OWN_ENTRIES_FILE: &str = "own_toggl_entries.json";
+/// File (inside the data directory) the entries seen running are persisted to.
+const RUNNING_ENTRIES_FILE: &str = "running_toggl_entries.json";
+
+/// Tracking actions, as used by Marvin's /api/track endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrackAction {
+    Start,
+    Stop,
+}
+
+impl TrackAction {
+    pub fn as_marvin_action(&self) -> &'static str {
+        match self {
+            TrackAction::Start => "START",
+            TrackAction::Stop => "STOP",
+        }
+    }
+}
+
+/// Set of Toggl entry IDs with the time they were added, persisted to the data
+/// directory. Entries older than `retention_ms` are forgotten.
+struct PersistedEntries {
+    file: &'static str,
+    retention_ms: i64,
+    entries: Mutex<HashMap<i64, i64>>,
+    /// Held while writing, so writes land in order
+    write: Mutex<()>,
+}
+
+impl PersistedEntries {
+    fn load(file: &'static str, retention_ms: i64) -> Self {
+        Self {
+            file,
+            retention_ms,
+            entries: Mutex::new(json::load_json(file).unwrap_or_default()),
+            write: Mutex::new(()),
+        }
+    }
+
+    fn contains(&self, entry_id: i64) -> bool {
+        self.entries.lock().unwrap().contains_key(&entry_id)
+    }
+
+    fn update<R>(&self, update: impl FnOnce(&mut HashMap<i64, i64>) -> R) -> R {
+        let now = now_ms();
+        let _write = self.write.lock().unwrap();
+        let (result, snapshot) = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, seen_at| now - *seen_at < self.retention_ms);
+            let result = update(&mut entries);
+            (result, entries.clone())
+        };
+        if let Err(err) = json::save_json(self.file, &snapshot) {
+            eprintln!("[ORIGIN] Could not persist {}: {}", self.file, err);
+        }
+        result
+    }
+
+    fn insert(&self, entry_id: i64) {
+        let now = now_ms();
+        self.update(|entries| entries.insert(entry_id, now));
+    }
+
+    fn remove(&self, entry_id: i64) -> bool {
+        self.update(|entries| entries.remove(&entry_id).is_some())
+    }
+}
+
+/// Toggl entries marvinhooks started on behalf of a Marvin webhook, with the time
+/// they were recorded. Reverse sync leaves these alone, also after a restart.
+/// Entries can run for a long time, so they're kept for a day rather than the echo window.
+static OWN_TOGGL_ENTRIES: LazyLock<PersistedEntries> =
+    LazyLock::new(|| PersistedEntries::load(OWN_ENTRIES_FILE, 1000 * 60 * 60 * 24));
+
+/// Toggl entries seen running, whether started by marvinhooks or in Toggl. Only
+/// the stop of one of these is a real running -> stopped transition; creating an
+/// entry that's already stopped or editing an old one doesn't change leisure.
+static RUNNING_ENTRIES: LazyLock<PersistedEntries> =
+    LazyLock::new(|| PersistedEntries::load(RUNNING_ENTRIES_FILE, 1000 * 60 * 60 * 24 * 7));
+
+/// Marvin tracking changes marvinhooks made itself. Marvin reports them back to us
+/// through the usual start/stop webhooks, which must not be synced to Toggl again.
+static MARVIN_ECHOES: LazyLock<Mutex<HashMap<(String, TrackAction), i64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Remember that we started Toggl entry `entry_id` ourselves.
+pub fn mark_own_entry(entry_id: i64) {
+    OWN_TOGGL_ENTRIES.insert(entry_id);
+    mark_running(entry_id);
+}
+
+/// Whether Toggl entry `entry_id` was started by marvinhooks.
+pub fn is_own_entry(entry_id: i64) -> bool {
+    OWN_TOGGL_ENTRIES.contains(entry_id)
+}
+
+/// Remember that Toggl entry `entry_id` is running, so its stop will be accounted for.
+pub fn mark_running(entry_id: i64) {
+    if !RUNNING_ENTRIES.contains(entry_id) {
+        RUNNING_ENTRIES.insert(entry_id);
+    }
+}
+
+/// marvinhooks stopped Toggl entry `entry_id` and accounted for it; Toggl reporting
+/// the stop back must not count it again.
+pub fn mark_own_stop(entry_id: i64) {
+    RUNNING_ENTRIES.remove(entry_id);
+}
+
+/// If Toggl entry `entry_id` was seen running, forget it and return true: its stop
+/// hasn't been accounted for yet.
+pub fn take_running(entry_id: i64) -> bool {
+    RUNNING_ENTRIES.remove(entry_id)
+}
+
+/// Remember that we're about to `action` Marvin task `task_id` ourselves.
+pub fn expect_marvin_echo(task_id: &str, action: TrackAction) {
+    let now = now_ms();
+    let mut echoes = MARVIN_ECHOES.lock().unwrap();
+    echoes.retain(|_, seen_at| now - *seen_at < ECHO_WINDOW_MS);
+    echoes.insert((task_id.to_string(), action), now);
+}
+
+/// If the Marvin event for `task_id`/`action` is the echo of a change we made,
+/// forget about it and return true.
+pub fn take_marvin_echo(task_id: &str, action: TrackAction) -> bool {
+    let now = now_ms();
+    let mut echoes = MARVIN_ECHOES.lock().unwrap();
+    match echoes.remove(&(task_id.to_string(), action)) {
+        Some(seen_at) => now - seen_at < ECHO_WINDOW_MS,
+        None => false,
+    }
+}
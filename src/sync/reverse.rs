@@ -0,0 +1,362 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fmt::Debug,
+    hash::Hash,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+use crate::{
+    api::{
+        client::MarvinClient,
+        requests::{CreateTaskRequest, DocSetter, TrackRequest},
+    },
+    cache::{
+        cache::{Cache, TOGGL_CLIENT_CACHE, TOGGL_PROJECT_CACHE, TOGGL_TASK_CACHE},
+        categories,
+    },
+    jobs::error::WebhookError,
+    models::tasks::Task,
+    routes::marvin_webhooks::{
+        marvin_client_from_env, remove_timestamp_prefix, toggl_client_from_env, workspace_id,
+    },
+    store::json,
+    sync::origin::{self, TrackAction},
+    toggl_api::{client::TogglClient, responses::TimeEntry},
+    tracking::{adherence, days, lock::TRACKING_LOCK},
+};
+
+/// Marvin's inbox, used as parent for tasks whose Toggl entry has no project
+const MARVIN_INBOX: &str = "unassigned";
+/// File (inside the data directory) the reverse sync state is persisted to.
+const REVERSE_STATE_FILE: &str = "reverse_sync.json";
+
+/// What reverse sync last saw running in Toggl.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ReverseSyncState {
+    /// Toggl entry that was running at the last check
+    entry_id: Option<i64>,
+    /// Marvin task we started tracking for that entry, if we did
+    marvin_task_id: Option<String>,
+    /// Toggl entry and the Marvin task found or created for it, kept even if
+    /// tracking it failed so the next attempt doesn't create the task again
+    #[serde(default)]
+    resolved: Option<(i64, String)>,
+    /// Description of the entry when its task title was last synced
+    #[serde(default)]
+    description: Option<String>,
+}
+
+static REVERSE_STATE: LazyLock<Mutex<ReverseSyncState>> =
+    LazyLock::new(|| Mutex::new(json::load_json(REVERSE_STATE_FILE).unwrap_or_default()));
+
+/// Change the state and write it to disk. Callers hold the tracking lock, so
+/// writes can't overtake each other.
+fn update_state<F: FnOnce(&mut ReverseSyncState)>(update: F) {
+    let snapshot = {
+        let mut state = REVERSE_STATE.lock().unwrap();
+        update(&mut state);
+        state.clone()
+    };
+    if let Err(err) = json::save_json(REVERSE_STATE_FILE, &snapshot) {
+        eprintln!("[REVERSE SYNC] Could not persist state: {}", err);
+    }
+}
+
+/// Names of the Toggl client/project/task an entry was filed under.
+#[derive(Debug, Clone, Default)]
+struct TogglNames {
+    client: Option<String>,
+    project: Option<String>,
+    task: Option<String>,
+}
+
+/// The key `cache` holds for Toggl ID `id`, if it's cached.
+async fn cached_key<K>(cache: &Cache<K, i64>, id: i64) -> Option<K>
+where
+    K: Eq + Hash + Clone + Debug,
+{
+    cache.entries().await.into_iter().find(|(_, cached)| *cached == id).map(|(key, _)| key)
+}
+
+/// Look the names up in the Toggl caches, and list them from Toggl (filling the
+/// caches) only for IDs the caches don't know.
+async fn toggl_names(
+    entry: &TimeEntry,
+    toggl_client: &TogglClient,
+    workspace_id: i64,
+) -> Result<TogglNames, WebhookError> {
+    let mut names = TogglNames::default();
+    let project_id = match entry.project_id {
+        Some(project_id) => project_id,
+        None => return Ok(names),
+    };
+
+    let (client_id, project) = match cached_key(&TOGGL_PROJECT_CACHE, project_id).await {
+        Some((client_id, name)) => (Some(client_id), name),
+        None => {
+            let projects = toggl_client.list_projects(workspace_id).await?;
+            for project in &projects {
+                if let Some(client_id) = project.client_id {
+                    TOGGL_PROJECT_CACHE.put((client_id, project.name.clone()), project.id).await;
+                }
+            }
+            match projects.into_iter().find(|p| p.id == project_id) {
+                Some(project) => (project.client_id, project.name),
+                None => return Ok(names),
+            }
+        }
+    };
+    names.project = Some(project);
+
+    if let Some(client_id) = client_id {
+        names.client = match cached_key(&TOGGL_CLIENT_CACHE, client_id).await {
+            Some(name) => Some(name),
+            None => {
+                let clients = toggl_client.list_clients(workspace_id, None, None).await?;
+                for client in &clients {
+                    TOGGL_CLIENT_CACHE.put(client.name.clone(), client.id).await;
+                }
+                clients.into_iter().find(|c| c.id == client_id).map(|c| c.name)
+            }
+        };
+    }
+
+    if let Some(task_id) = entry.task_id {
+        names.task = match cached_key(&TOGGL_TASK_CACHE, task_id).await {
+            Some((_, name)) => Some(name),
+            None => {
+                let tasks = toggl_client.get_project_tasks(workspace_id, project_id).await?;
+                for task in &tasks {
+                    TOGGL_TASK_CACHE.put((project_id, task.name.clone()), task.id).await;
+                }
+                tasks.into_iter().find(|t| t.id == task_id).map(|t| t.name)
+            }
+        };
+    }
+
+    Ok(names)
+}
+
+/// Candidate Marvin ancestor chains (outermost first) for a set of Toggl names.
+/// This inverts the depth rules of `resolve_marvin_task_to_toggl`:
+///   1 level:  client = project = parent
+///   2 levels: client = grandparent, project = parent
+///   3 levels: client, project, task = parent
+fn ancestor_chains(names: &TogglNames) -> Vec<Vec<String>> {
+    match (&names.client, &names.project, &names.task) {
+        (Some(client), Some(project), Some(task)) => {
+            vec![vec![client.clone(), project.clone(), task.clone()]]
+        }
+        (Some(client), Some(project), None) if client == project => {
+            vec![vec![client.clone()], vec![client.clone(), project.clone()]]
+        }
+        (Some(client), Some(project), None) => vec![vec![client.clone(), project.clone()]],
+        _ => vec![],
+    }
+}
+
+fn same_title(marvin_title: &str, toggl_name: &str) -> bool {
+    remove_timestamp_prefix(marvin_title.trim()) == toggl_name.trim()
+}
+
+/// Find (or create) the Marvin task a foreign Toggl entry corresponds to.
+async fn resolve_toggl_entry_to_marvin(
+    entry: &TimeEntry,
+    marvin_client: &MarvinClient,
+    toggl_client: &TogglClient,
+    workspace_id: i64,
+) -> Result<String, WebhookError> {
+    let description = entry.description.as_deref().unwrap_or("").trim().to_string();
+    if description.is_empty() {
+        return Err(WebhookError::DataError(format!(
+            "Toggl entry {} has no description",
+            entry.id
+        )));
+    }
+
+    let names = toggl_names(entry, toggl_client, workspace_id).await?;
+    println!("[REVERSE SYNC] Entry {} -> {:?} '{}'", entry.id, names, description);
+
+    let chains = ancestor_chains(&names);
+    let mut parent_id = None;
+    for chain in &chains {
+        parent_id = categories::find_path(chain, same_title).await?;
+        if parent_id.is_some() {
+            break;
+        }
+    }
+    let parent_id = parent_id.unwrap_or_else(|| {
+        if !chains.is_empty() {
+            println!("[REVERSE SYNC] No Marvin category matches {:?}, using the inbox", names);
+        }
+        MARVIN_INBOX.to_string()
+    });
+
+    let children = marvin_client.get_children(&parent_id).await?;
+    if let Some(task) = children
+        .iter()
+        .find(|task| task.done != Some(true) && same_title(&task.title, &description))
+    {
+        return Ok(task.id.clone());
+    }
+
+    println!("[REVERSE SYNC] Creating Marvin task '{}' in {}", description, parent_id);
+    let request = CreateTaskRequest {
+        title: description,
+        parent_id: Some(parent_id),
+        day: Some(days::day_of(Utc::now()).format("%Y-%m-%d").to_string()),
+        ..Default::default()
+    };
+    let task = marvin_client.create_task(&request).await?;
+    Ok(task.id)
+}
+
+async fn track_in_marvin(
+    marvin_client: &MarvinClient,
+    task_id: &str,
+    action: TrackAction,
+) -> Result<(), WebhookError> {
+    // Marvin will send us the matching start/stop webhook; don't sync it back
+    origin::expect_marvin_echo(task_id, action);
+    let request = TrackRequest {
+        task_id: task_id.to_string(),
+        action: action.as_marvin_action().to_string(),
+    };
+    let response = marvin_client.track(&request).await?;
+    if !response.issues.is_empty() {
+        println!("[REVERSE SYNC] Marvin reported issues: {:?}", response.issues);
+    }
+    Ok(())
+}
+
+/// Rename the Marvin task after the running entry if the entry was renamed in
+/// Toggl. Left for the next check if the task changed in Marvin in the meantime.
+async fn mirror_rename(
+    entry: &TimeEntry,
+    task_id: &str,
+    synced: Option<&str>,
+) -> Result<(), WebhookError> {
+    let description = entry.description.as_deref().unwrap_or("").trim();
+    if description.is_empty() || synced == Some(description) {
+        return Ok(());
+    }
+
+    let marvin_client = marvin_client_from_env()?;
+    let task = marvin_client.read_doc_as::<Task>(task_id).await?;
+    if !same_title(&task.doc.title, description) {
+        println!("[REVERSE SYNC] Toggl entry {} renamed, renaming Marvin task {} to '{}'", entry.id, task_id, description);
+        marvin_client
+            .update_doc_as::<Task>(task_id, task.rev.as_deref(), vec![DocSetter::title(description)])
+            .await?;
+    }
+    update_state(|state| state.description = Some(description.to_string()));
+    Ok(())
+}
+
+/// Bring Marvin in line with what's running in Toggl right now.
+/// Entries started by marvinhooks are ignored; entries started anywhere else get
+/// a Marvin task tracked alongside them, which is stopped when the entry goes away.
+/// Callers must hold the tracking lock.
+pub async fn sync_running_entry(current: Option<TimeEntry>) -> Result<(), WebhookError> {
+    if let Some(entry) = &current {
+        origin::mark_running(entry.id);
+    }
+    let previous = REVERSE_STATE.lock().unwrap().clone();
+    let current_id = current.as_ref().map(|entry| entry.id);
+    if previous.entry_id == current_id {
+        if let (Some(entry), Some(task_id)) = (&current, &previous.marvin_task_id) {
+            mirror_rename(entry, task_id, previous.description.as_deref()).await?;
+        }
+        return Ok(());
+    }
+
+    let marvin_client = marvin_client_from_env()?;
+
+    // The entry we mirrored is gone (stopped or replaced): stop Marvin as well
+    if let Some(task_id) = &previous.marvin_task_id {
+        println!("[REVERSE SYNC] Toggl entry {:?} ended, stopping Marvin task {}", previous.entry_id, task_id);
+        track_in_marvin(&marvin_client, task_id, TrackAction::Stop).await?;
+        update_state(|state| state.marvin_task_id = None);
+    }
+
+    let mut marvin_task_id = None;
+    let mut description = None;
+    if let Some(entry) = current.filter(|entry| !origin::is_own_entry(entry.id)) {
+        let task_id = match previous.resolved {
+            Some((entry_id, task_id)) if entry_id == entry.id => task_id,
+            _ => {
+                let toggl_client = toggl_client_from_env()?;
+                let workspace_id = workspace_id()?;
+                let task_id = resolve_toggl_entry_to_marvin(
+                    &entry,
+                    &marvin_client,
+                    &toggl_client,
+                    workspace_id,
+                )
+                .await?;
+                update_state(|state| state.resolved = Some((entry.id, task_id.clone())));
+                task_id
+            }
+        };
+        println!("[REVERSE SYNC] Toggl entry {} started outside Marvin, tracking {}", entry.id, task_id);
+        track_in_marvin(&marvin_client, &task_id, TrackAction::Start).await?;
+        if let Err(err) = adherence::check_foreign_entry(&entry, &task_id).await {
+            println!("[REVERSE SYNC] Time block check failed: {}", err.chain().join(": "));
+        }
+        marvin_task_id = Some(task_id);
+        description = entry.description.as_deref().map(|desc| desc.trim().to_string());
+    }
+
+    update_state(|state| {
+        state.entry_id = current_id;
+        state.marvin_task_id = marvin_task_id;
+        state.description = description;
+    });
+    Ok(())
+}
+
+/// Poll Toggl for the running entry every `REVERSE_SYNC_INTERVAL_SECS` seconds
+/// and mirror entries started outside marvinhooks into Marvin.
+/// Only needed when no Toggl webhook subscription points at `/toggl-webhook`;
+/// does nothing if the variable isn't set.
+pub async fn run_reverse_sync() {
+    let interval_secs: u64 = match env::var("REVERSE_SYNC_INTERVAL_SECS") {
+        Ok(val) => match val.parse() {
+            Ok(secs) if secs > 0 => secs,
+            _ => {
+                eprintln!("REVERSE_SYNC_INTERVAL_SECS is not a positive number, reverse sync disabled");
+                return;
+            }
+        },
+        Err(_) => return,
+    };
+    println!("[REVERSE SYNC] Polling Toggl every {}s", interval_secs);
+
+    loop {
+        sleep(Duration::from_secs(interval_secs)).await;
+
+        let toggl_client = match toggl_client_from_env() {
+            Ok(client) => client,
+            Err(err) => {
+                println!("[REVERSE SYNC] {}", err);
+                continue;
+            }
+        };
+
+        let _guard = TRACKING_LOCK.lock().await;
+        let current = match toggl_client.get_current_time_entry().await {
+            Ok(current) => current,
+            Err(err) => {
+                println!("[REVERSE SYNC] Could not fetch current entry: {}", err);
+                continue;
+            }
+        };
+        if let Err(err) = sync_running_entry(current).await {
+            println!("[REVERSE SYNC] Failed: {}", err.chain().join(": "));
+        }
+    }
+}
@@ -1,15 +1,21 @@
-use crate::toggl_api::error::TogglError;
+use crate::toggl_api::error::{TogglError, error_message};
 use crate::toggl_api::requests::*;
 use crate::toggl_api::responses::*;
-use crate::LEISURE_BALANCE;
-use crate::LEISURE_RATE;
+use crate::retry::{jitter, retry_after};
+use crate::sync::origin;
+use crate::tracking::days::day_segments;
+use crate::tracking::leisure::accrue_leisure_between;
+use crate::tracking::policy::{PolicyOutcome, policy_for};
 use chrono::DateTime;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use reqwest::{Client as HttpClient, Method, StatusCode};
-use std::sync::atomic::Ordering;
-use std::time::SystemTime;
+use reqwest::{Client as HttpClient, Method, RequestBuilder, Response, StatusCode};
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
 
 /// The default base URL for Toggl Track API v9.
 pub const TOGGL_BASE_URL: &str = "https://api.track.toggl.com/api/v9";
@@ -25,6 +31,79 @@ pub enum StopCondition {
     IfDifferent { project_id: Option<i64>, description: String },
 }
 
+/// Maximum number of retries for rate-limited or failed requests
+const MAX_RETRIES: u32 = 4;
+/// Initial backoff delay in seconds
+const INITIAL_BACKOFF_SECS: u64 = 1;
+/// Longest time a request waits for Toggl's quota to reset before it's sent anyway
+const MAX_QUOTA_WAIT: Duration = Duration::from_secs(5 * 60);
+
+/// When Toggl's API quota resets, if the last response said it's used up.
+/// Shared by all clients, since the quota is per user.
+static QUOTA_RESETS_AT: LazyLock<Mutex<Option<Instant>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Remember Toggl's quota headers. Returns the remaining quota, if Toggl sent it.
+fn record_quota(resp: &Response) -> Option<i64> {
+    let header = |name: &str| -> Option<i64> {
+        resp.headers().get(name)?.to_str().ok()?.trim().parse().ok()
+    };
+    let remaining = header("X-Toggl-Quota-Remaining")?;
+    if remaining <= 0
+        && let Some(resets_in) = header("X-Toggl-Quota-Resets-In")
+    {
+        let resets_at = Instant::now() + Duration::from_secs(resets_in.max(0) as u64);
+        *QUOTA_RESETS_AT.lock().unwrap() = Some(resets_at);
+    }
+    Some(remaining)
+}
+
+/// Wait until the quota resets if the last response said it's used up.
+/// The reset time stays in place for every other request until it has passed.
+async fn wait_for_quota() {
+    let resets_at = match *QUOTA_RESETS_AT.lock().unwrap() {
+        Some(resets_at) => resets_at,
+        None => return,
+    };
+    let wait = resets_at.saturating_duration_since(Instant::now()).min(MAX_QUOTA_WAIT);
+    if !wait.is_zero() {
+        tracing::warn!(?wait, "Toggl quota used up, waiting for it to reset");
+        sleep(wait).await;
+    }
+    let mut quota_resets_at = QUOTA_RESETS_AT.lock().unwrap();
+    // Unless a newer response moved the reset time
+    if *quota_resets_at == Some(resets_at) && resets_at <= Instant::now() {
+        *quota_resets_at = None;
+    }
+}
+
+/// Headers Toggl says how long to wait in (seconds), preferred first
+const RETRY_HEADERS: &[&str] = &["X-Toggl-Quota-Resets-In", "Retry-After"];
+
+/// Error for a failed request, keeping Toggl's explanation of what was wrong with it.
+async fn error_response(endpoint: &str, resp: Response) -> TogglError {
+    let status = resp.status();
+    let body = resp.text().await.unwrap_or_default();
+    let message = error_message(&body);
+    tracing::warn!(endpoint, %status, %message, "Toggl request failed");
+    TogglError::StatusCodeError {
+        status,
+        endpoint: endpoint.to_string(),
+        message,
+        body,
+    }
+}
+
+/// What `TogglClient::stop_time_entry_at` did.
+#[derive(Debug, Clone)]
+pub struct StoppedEntry {
+    /// The updated entry (its last day, if it was split), or the entry as it was if deleted
+    pub entry: TimeEntry,
+    /// Where the entry was stopped after the entry policy; None if it was deleted
+    pub stop: Option<DateTime<Utc>>,
+    /// Change to the leisure balance (ms)
+    pub leisure_change: i64,
+}
+
 #[derive(Debug, Clone)]
 pub struct TogglClient {
     http: HttpClient,
@@ -57,20 +136,89 @@ impl TogglClient {
     // Utility methods (GET, POST, etc.)
     //--------------------------------------------------------------------------
 
-    async fn get_json<T>(&self, endpoint: &str) -> Result<T, TogglError>
+    /// Send one request to `endpoint`, with `build` adding the query and body, and
+    /// return the successful response.
+    /// - waits while Toggl's quota (shared by all clients) is used up
+    /// - retries 429s and quota errors after `X-Toggl-Quota-Resets-In` or `Retry-After`
+    /// - retries server errors and network errors if the request is safe to repeat
+    /// - turns other failures into `TogglError::StatusCodeError` with Toggl's message
+    async fn send<F>(
+        &self,
+        method: Method,
+        endpoint: &str,
+        build: F,
+    ) -> Result<Response, TogglError>
     where
-        T: serde::de::DeserializeOwned,
+        F: Fn(RequestBuilder) -> RequestBuilder,
     {
         let url = format!("{}/{}", self.base_url, endpoint);
-        let req = self
-            .http
-            .request(Method::GET, &url)
-            .basic_auth(&self.username, Some(&self.password));
-        let resp = req.send().await?;
-        println!("{:#?}", resp);
-        if !resp.status().is_success() {
-            return Err(TogglError::StatusCodeError(resp.status()));
+        // POSTs create things; sending one again after a server error could duplicate it
+        let idempotent = method != Method::POST;
+        let mut retries = 0;
+        let mut backoff = Duration::from_secs(INITIAL_BACKOFF_SECS);
+
+        loop {
+            wait_for_quota().await;
+            let req = build(
+                self.http
+                    .request(method.clone(), &url)
+                    .basic_auth(&self.username, Some(&self.password)),
+            );
+            let resp = match req.send().await {
+                Ok(resp) => resp,
+                Err(err)
+                    if idempotent
+                        && retries < MAX_RETRIES
+                        && (err.is_timeout() || err.is_connect()) =>
+                {
+                    let delay = backoff + jitter(backoff);
+                    tracing::warn!(
+                        %method, endpoint, error = %err, retry = retries + 1, ?delay,
+                        "Toggl request failed, retrying"
+                    );
+                    sleep(delay).await;
+                    retries += 1;
+                    backoff *= 2;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let status = resp.status();
+            let quota = record_quota(&resp);
+            tracing::debug!(%method, endpoint, %status, quota_remaining = ?quota, "Toggl response");
+
+            let rate_limited = status == StatusCode::TOO_MANY_REQUESTS
+                || (status == StatusCode::PAYMENT_REQUIRED && quota.is_some());
+            let retryable = rate_limited || (idempotent && status.is_server_error());
+            if retryable && retries < MAX_RETRIES {
+                let delay = match rate_limited {
+                    // Capped like the quota wait; the tracking lock may be held meanwhile
+                    true => retry_after(&resp, RETRY_HEADERS).unwrap_or(backoff).min(MAX_QUOTA_WAIT),
+                    false => backoff,
+                };
+                let delay = delay + jitter(delay);
+                tracing::warn!(
+                    %method, endpoint, %status, retry = retries + 1, max_retries = MAX_RETRIES, ?delay,
+                    "Toggl request not accepted, retrying"
+                );
+                sleep(delay).await;
+                retries += 1;
+                backoff *= 2; // Exponential backoff
+                continue;
+            }
+
+            if !status.is_success() {
+                return Err(error_response(endpoint, resp).await);
+            }
+            return Ok(resp);
         }
+    }
+
+    async fn get_json<T>(&self, endpoint: &str) -> Result<T, TogglError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let resp = self.send(Method::GET, endpoint, |req| req).await?;
         Ok(resp.json::<T>().await?)
     }
 
@@ -79,17 +227,7 @@ impl TogglClient {
         T: serde::de::DeserializeOwned,
         Q: serde::Serialize,
     {
-        let url = format!("{}/{}", self.base_url, endpoint);
-        let req = self
-            .http
-            .request(Method::GET, &url)
-            .basic_auth(&self.username, Some(&self.password))
-            .query(query);
-        let resp = req.send().await?;
-        println!("{:#?}", resp);
-        if !resp.status().is_success() {
-            return Err(TogglError::StatusCodeError(resp.status()));
-        }
+        let resp = self.send(Method::GET, endpoint, |req| req.query(query)).await?;
         Ok(resp.json::<T>().await?)
     }
 
@@ -98,44 +236,32 @@ impl TogglClient {
         Rq: serde::Serialize,
         Rs: serde::de::DeserializeOwned,
     {
-        let url = format!("{}/{}", self.base_url, endpoint);
-        let req = self
-            .http
-            .request(Method::POST, &url)
-            .basic_auth(&self.username, Some(&self.password))
-            .json(body);
-        println!("{:#?}", req);
-        let resp = req.send().await?;
-        println!("{:#?}", resp);
-        if !resp.status().is_success() {
-            println!("{}", resp.text().await.unwrap());
-            return Err(TogglError::StatusCodeError(StatusCode::INTERNAL_SERVER_ERROR));
-            //return Err(TogglError::StatusCodeError(resp.status()));
-        }
+        let resp = self.send(Method::POST, endpoint, |req| req.json(body)).await?;
         Ok(resp.json::<Rs>().await?)
     }
 
-    /// A generic helper for PATCH requests with no request body, returning a JSON response.
-    async fn patch_json_no_body<Rs>(&self, endpoint: &str) -> Result<Rs, TogglError>
+    async fn put_json<Rq, Rs>(&self, endpoint: &str, body: &Rq) -> Result<Rs, TogglError>
     where
+        Rq: serde::Serialize,
         Rs: serde::de::DeserializeOwned,
     {
-        let url = format!("{}/{}", self.base_url, endpoint);
-
-        let req = self
-            .http
-            .request(Method::PATCH, &url)
-            .basic_auth(&self.username, Some(&self.password));
-
-        let resp = req.send().await?;
-        println!("{:#?}", resp);
-        if !resp.status().is_success() {
-            return Err(TogglError::StatusCodeError(resp.status()));
-        }
+        let resp = self.send(Method::PUT, endpoint, |req| req.json(body)).await?;
         Ok(resp.json::<Rs>().await?)
     }
 
+    async fn delete(&self, endpoint: &str) -> Result<(), TogglError> {
+        self.send(Method::DELETE, endpoint, |req| req).await?;
+        Ok(())
+    }
 
+    /// A generic helper for PATCH requests with no request body, returning a JSON response.
+    async fn patch_json_no_body<Rs>(&self, endpoint: &str) -> Result<Rs, TogglError>
+    where
+        Rs: serde::de::DeserializeOwned,
+    {
+        let resp = self.send(Method::PATCH, endpoint, |req| req).await?;
+        Ok(resp.json::<Rs>().await?)
+    }
 
     //--------------------------------------------------------------------------
     // Endpoint methods
@@ -206,6 +332,76 @@ impl TogglClient {
         self.post_json(&endpoint, &body).await
     }
 
+    /// Create a time entry from a fully specified request, e.g. a completed entry
+    /// with explicit `start`, `stop` and `duration`.
+    /// POST /api/v9/workspaces/{workspace_id}/time_entries
+    pub async fn create_time_entry(
+        &self,
+        workspace_id: i64,
+        req: &CreateTimeEntryRequest,
+    ) -> Result<TimeEntry, TogglError> {
+        let endpoint = format!("workspaces/{}/time_entries", workspace_id);
+        self.post_json(&endpoint, req).await
+    }
+
+    /// Change an existing time entry, e.g. to move its start or stop.
+    /// PUT /api/v9/workspaces/{workspace_id}/time_entries/{time_entry_id}
+    pub async fn update_time_entry(
+        &self,
+        workspace_id: i64,
+        time_entry_id: i64,
+        req: &UpdateTimeEntryRequest,
+    ) -> Result<TimeEntry, TogglError> {
+        let endpoint = format!("workspaces/{}/time_entries/{}", workspace_id, time_entry_id);
+        self.put_json(&endpoint, req).await
+    }
+
+    /// Turn a stopped entry back into a running one, by clearing its stop and making
+    /// the duration negative. Fails if Toggl keeps the entry stopped.
+    /// PUT /api/v9/workspaces/{workspace_id}/time_entries/{time_entry_id}
+    pub async fn restart_time_entry(
+        &self,
+        workspace_id: i64,
+        time_entry_id: i64,
+    ) -> Result<TimeEntry, TogglError> {
+        let endpoint = format!("workspaces/{}/time_entries/{}", workspace_id, time_entry_id);
+        // `stop` has to be sent as null; UpdateTimeEntryRequest leaves it out when None
+        let body = serde_json::json!({ "stop": null, "duration": -1 });
+        let entry: TimeEntry = self.put_json(&endpoint, &body).await?;
+        if entry.stop.is_some() || entry.duration.is_some_and(|duration| duration >= 0) {
+            return Err(TogglError::DataError(format!(
+                "Toggl kept entry {} stopped instead of restarting it",
+                time_entry_id
+            )));
+        }
+        Ok(entry)
+    }
+
+    /// Delete a time entry.
+    /// DELETE /api/v9/workspaces/{workspace_id}/time_entries/{time_entry_id}
+    pub async fn delete_time_entry(&self, workspace_id: i64, time_entry_id: i64) -> Result<(), TogglError> {
+        let endpoint = format!("workspaces/{}/time_entries/{}", workspace_id, time_entry_id);
+        self.delete(&endpoint).await
+    }
+
+    /// List the current user's time entries that started between two dates.
+    /// Dates can be YYYY-MM-DD or RFC3339.
+    /// GET /api/v9/me/time_entries
+    pub async fn list_time_entries(
+        &self,
+        start_date: &str,
+        end_date: &str,
+    ) -> Result<Vec<TimeEntry>, TogglError> {
+        #[derive(Serialize)]
+        struct QueryParams<'a> {
+            start_date: &'a str,
+            end_date: &'a str,
+        }
+
+        let query = QueryParams { start_date, end_date };
+        self.get_json_with_query("me/time_entries", &query).await
+    }
+
     /// Create a client in the specified workspace.
     /// POST /api/v9/workspaces/{workspace_id}/clients
     pub async fn create_client(
@@ -325,19 +521,7 @@ impl TogglClient {
             "workspaces/{}/time_entries/{}/stop",
             workspace_id, time_entry_id
         );
-        let url = format!("{}/{}", self.base_url, endpoint);
-
-        let req = self
-            .http
-            .request(Method::PATCH, &url)
-            .basic_auth(&self.username, Some(&self.password));
-
-        let resp = req.send().await?;
-        println!("{:#?}", resp);
-
-        if !resp.status().is_success() {
-            return Err(TogglError::StatusCodeError(resp.status()));
-        }
+        let resp = self.send(Method::PATCH, &endpoint, |req| req).await?;
 
         // Get the response text first to handle empty/null responses
         let text = resp.text().await?;
@@ -364,33 +548,118 @@ impl TogglClient {
 
     pub async fn get_current_time_entry(&self) -> Result<Option<TimeEntry>, TogglError> {
         let endpoint = "me/time_entries/current";
-        let url = format!("{}/{}", self.base_url, endpoint);
+        let resp = match self.send(Method::GET, endpoint, |req| req).await {
+            Ok(resp) => resp,
+            // Means no current time entry is running
+            Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        // Possibly a 200 with "null" => handle gracefully
+        Ok(resp.json::<Option<TimeEntry>>().await?)
+    }
 
-        let req = self
-            .http
-            .request(Method::GET, &url)
-            .basic_auth(&self.username, Some(&self.password));
+    /// Stop `entry` as of `stop` instead of now, e.g. to trim an entry that was left running.
+    /// The entry policy of its project applies: too short entries are deleted, and the
+    /// duration is rounded. A duration rounded up past now moves the start back instead,
+    /// so the entry never ends in the future. Leisure is accounted for the final duration.
+    pub async fn stop_time_entry_at(
+        &self,
+        entry: &TimeEntry,
+        stop: DateTime<Utc>,
+        productivity_override: Option<bool>,
+    ) -> Result<StoppedEntry, TogglError> {
+        let start: DateTime<Utc> = entry.start.parse().map_err(|_| {
+            TogglError::DataError(format!("Invalid start time '{}'", entry.start))
+        })?;
+        let now = Utc::now();
+        let stop = stop.min(now).max(start);
+        let ws_id = entry.workspace_id.ok_or_else(|| {
+            TogglError::DataError("Time entry has no workspace_id".to_string())
+        })?;
+
+        let tracked_ms = stop.signed_duration_since(start).num_milliseconds();
+        let duration_ms = match policy_for(entry.project_id).apply(tracked_ms) {
+            PolicyOutcome::Delete => {
+                println!("[TOGGL] Entry {} is too short, deleting it", entry.id);
+                self.delete_time_entry(ws_id, entry.id).await?;
+                origin::mark_own_stop(entry.id);
+                return Ok(StoppedEntry { entry: entry.clone(), stop: None, leisure_change: 0 });
+            }
+            PolicyOutcome::Keep { duration_ms } => duration_ms,
+        };
+        let duration = chrono::Duration::milliseconds(duration_ms);
+        let (moved_start, stop) = if start + duration > now {
+            (Some(now - duration), now)
+        } else {
+            (None, start + duration)
+        };
+        let start = moved_start.unwrap_or(start);
+        let req = UpdateTimeEntryRequest {
+            start: moved_start.map(|start| start.to_rfc3339()),
+            stop: Some(stop.to_rfc3339()),
+            duration: Some(duration_ms / 1000),
+            ..Default::default()
+        };
+        let updated = self.update_time_entry(ws_id, entry.id, &req).await?;
 
-        let resp = req.send().await?;
+        let change = accrue_leisure_between(entry.tags.as_deref(), productivity_override, start, stop);
+        origin::mark_own_stop(entry.id);
+        // The segment that ends at the stop, so a resume continues today's part
+        let segment = self.split_at_midnight(&updated).await?.pop().unwrap_or(updated);
+        Ok(StoppedEntry { entry: segment, stop: Some(stop), leisure_change: change })
+    }
 
+    /// Split a stopped entry that crosses midnight in the user's timezone into one
+    /// entry per day: the entry itself keeps the first day, and new entries with the
+    /// same description, project, task and tags cover the following days.
+    /// Leisure is not touched. Returns all segments, in order.
+    pub async fn split_at_midnight(&self, entry: &TimeEntry) -> Result<Vec<TimeEntry>, TogglError> {
+        let start: Option<DateTime<Utc>> = entry.start.parse().ok();
+        let stop: Option<DateTime<Utc>> = entry.stop.as_deref().and_then(|stop| stop.parse().ok());
+        let (start, stop, ws_id) = match (start, stop, entry.workspace_id) {
+            (Some(start), Some(stop), Some(ws_id)) => (start, stop, ws_id),
+            _ => return Ok(vec![entry.clone()]),
+        };
+        let segments = day_segments(start, stop);
+        if segments.len() < 2 {
+            return Ok(vec![entry.clone()]);
+        }
 
-        match resp.status() {
-            StatusCode::OK => {
-                // Attempt to deserialize the time entry
-                let te = resp.json::<TimeEntry>().await?;
-                Ok(Some(te))
-            }
-            StatusCode::NOT_FOUND => {
-                // Means no current time entry is running
-                Ok(None)
-            }
-            s if s.is_success() => {
-                // Possibly a 200 with "null" => handle gracefully
-                let maybe_te = resp.json::<Option<TimeEntry>>().await?;
-                Ok(maybe_te)
-            }
-            _ => Err(TogglError::StatusCodeError(resp.status())),
+        println!("[TOGGL] Entry {} spans {} days, splitting it", entry.id, segments.len());
+        let (_, first_start, first_stop) = segments[0];
+        let req = UpdateTimeEntryRequest {
+            stop: Some(first_stop.to_rfc3339()),
+            duration: Some((first_stop - first_start).num_seconds()),
+            ..Default::default()
+        };
+        let mut split = vec![self.update_time_entry(ws_id, entry.id, &req).await?];
+
+        for (_, segment_start, segment_stop) in segments.into_iter().skip(1) {
+            let body = CreateTimeEntryRequest {
+                billable: entry.billable,
+                created_with: "MarvinWebhook".to_string(),
+                description: entry.description.clone(),
+                duration: (segment_stop - segment_start).num_seconds(),
+                duronly: None,
+                event_metadata: None,
+                pid: None,
+                project_id: entry.project_id,
+                shared_with_user_ids: None,
+                start: segment_start.to_rfc3339(),
+                start_date: None,
+                stop: Some(segment_stop.to_rfc3339()),
+                tag_action: None,
+                tag_ids: None,
+                tags: entry.tags.clone(),
+                task_id: entry.task_id,
+                tid: None,
+                user_id: None,
+                workspace_id: ws_id,
+            };
+            // Created stopped, so Toggl reporting it doesn't count as a stop
+            split.push(self.create_time_entry(ws_id, &body).await?);
         }
+        Ok(split)
     }
 
     pub async fn stop_current_time_entry(
@@ -443,39 +712,20 @@ impl TogglClient {
             .expect("Invalid datetime format");
         // Get the current time in UTC.
         let now: DateTime<Utc> = Utc::now();
-        // Compute the difference as a chrono Duration.
-        let diff = target.signed_duration_since(now);
-        // Convert the difference to milliseconds.
-        let diff_ms = diff.num_milliseconds();
-        let rate = *LEISURE_RATE.lock().unwrap();
-        let time_change = -1.0 * rate * diff_ms as f64;
-       // Update third time count: don't update if neutral | no tags, add if productive, remove if unproductive
-        // Third time rate should be an envvar so I don't need to rebuild
-        match productivity_override {
-            None => {
-                match current_te.tags {
-                    Some(tags) => {
-                         for tag in tags {
-                            if tag == "productive" {
-                                LEISURE_BALANCE.fetch_add(time_change as i64, Ordering::SeqCst);
-                            } else if tag == "unproductive" {
-                                LEISURE_BALANCE.fetch_add(diff_ms as i64, Ordering::SeqCst);
-                            }
-                        }
-                    },
-                    None => ()
-                }
-            }
-            Some(true) => {
-                LEISURE_BALANCE.fetch_add(time_change as i64, Ordering::SeqCst);
-            }
-            Some(false) => {
-                LEISURE_BALANCE.fetch_add(diff_ms as i64, Ordering::SeqCst);
-            }
+        let duration_ms = now.signed_duration_since(target).num_milliseconds();
+
+        // 4) Entries the policy deletes or rounds, and entries crossing midnight, are
+        // stopped at their final duration and split
+        if policy_for(current_te.project_id).apply(duration_ms) != (PolicyOutcome::Keep { duration_ms })
+            || day_segments(target, now).len() > 1
+        {
+            let stopped = self
+                .stop_time_entry_at(&current_te, now, productivity_override)
+                .await?;
+            return Ok(Some(stopped.entry));
         }
 
-
-        // 4) Extract workspace
+        // 5) Extract workspace
         let ws_id = match current_te.workspace_id {
             Some(id) => id,
             None => {
@@ -488,8 +738,15 @@ impl TogglClient {
             }
         };
 
-        // 5) Call stop_time_entry
+        // 6) Call stop_time_entry
         let stopped_te = self.stop_time_entry(ws_id, current_te.id).await?;
+        // Toggl will report this stop back to us; it's accounted for here
+        origin::mark_own_stop(current_te.id);
+
+        // Update third time count only once the stop went through, so a retried job
+        // doesn't count it twice: don't update if neutral | no tags, add if productive,
+        // remove if unproductive
+        accrue_leisure_between(current_te.tags.as_deref(), productivity_override, target, now);
         Ok(Some(stopped_te))
     }
 }
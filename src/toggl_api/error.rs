@@ -7,8 +7,16 @@ pub enum TogglError {
     #[error("HTTP error: {0}")]
     HttpError(#[from] reqwest::Error),
 
-    #[error("Server returned status code {0}")]
-    StatusCodeError(StatusCode),
+    /// Toggl answered, but not with success
+    #[error("Toggl returned status code {status} for {endpoint}: {message}")]
+    StatusCodeError {
+        status: StatusCode,
+        endpoint: String,
+        /// Toggl's explanation, taken from the body
+        message: String,
+        /// The body as Toggl sent it
+        body: String,
+    },
 
     #[error("Invalid or unexpected data: {0}")]
     DataError(String),
@@ -16,3 +24,68 @@ pub enum TogglError {
     #[error("Other error: {0}")]
     Other(String),
 }
+
+/// Toggl's error message from a response body. Toggl sends a JSON string, a list of
+/// strings, an object with a message, or plain text.
+pub fn error_message(body: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::String(message)) => message,
+        Ok(serde_json::Value::Array(messages)) => messages
+            .iter()
+            .map(|message| match message {
+                serde_json::Value::String(message) => message.clone(),
+                other => other.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("; "),
+        Ok(serde_json::Value::Object(object)) => ["message", "error", "error_message"]
+            .iter()
+            .find_map(|key| object.get(*key).and_then(|value| value.as_str()))
+            .map(str::to_string)
+            .unwrap_or_else(|| body.trim().to_string()),
+        _ => body.trim().to_string(),
+    }
+}
+
+impl TogglError {
+    /// The status code Toggl answered with, if it answered.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            TogglError::StatusCodeError { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Whether Toggl (or the way to it) is down or overloaded, rather than the request
+    /// being wrong: network errors, rate limits, used up quota and server errors.
+    pub fn is_outage(&self) -> bool {
+        match self {
+            TogglError::HttpError(_) => true,
+            TogglError::StatusCodeError { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::PAYMENT_REQUIRED
+                    || status.is_server_error()
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether Toggl rejected the request because it refers to a project, task, tag
+    /// or client that doesn't exist (anymore).
+    pub fn is_stale_reference(&self) -> bool {
+        match self {
+            TogglError::StatusCodeError { status, message, .. } => {
+                let message = message.to_lowercase();
+                *status == StatusCode::NOT_FOUND
+                    || (*status == StatusCode::BAD_REQUEST
+                        && ["not found", "does not exist", "doesn't exist", "invalid", "deleted"]
+                            .iter()
+                            .any(|reason| message.contains(reason))
+                        && ["project", "task", "tag", "client"]
+                            .iter()
+                            .any(|kind| message.contains(kind)))
+            }
+            _ => false,
+        }
+    }
+}
@@ -97,6 +97,47 @@ fn default_negative_duration() -> i64 {
     -1
 }
 
+// -------------------------
+// PUT /api/v9/workspaces/{workspace_id}/time_entries/{time_entry_id}
+// -------------------------
+
+/// Changes to an existing time entry. Fields left as None are not sent and stay as they are.
+/// Setting `stop` on a running entry stops it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateTimeEntryRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<i64>,
+
+    /// Start time in UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+
+    /// Stop time in UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<String>,
+
+    /// Duration in seconds; negative for running entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<i64>,
+
+    /// Can be "add" or "delete", applies to `tags`/`tag_ids`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_action: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_ids: Option<Vec<i64>>,
+
+    /// Names of tags to add/remove
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+}
+
 // -------------------------
 // POST /api/v9/workspaces/{workspace_id}/clients
 // -------------------------
@@ -108,6 +108,8 @@ pub struct TimeEntry {
     #[serde(default)]
     pub project_id: Option<i64>,
     #[serde(default)]
+    pub task_id: Option<i64>,
+    #[serde(default)]
     pub workspace_id: Option<i64>,
     #[serde(default)]
     pub tags: Option<Vec<String>>,
@@ -123,3 +125,74 @@ pub struct Tag {
     pub extra: HashMap<String, serde_json::Value>,
 }
 
+
+/// Body of a Toggl webhook delivery (POST to the subscription's callback URL)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TogglWebhookEvent {
+    #[serde(default)]
+    pub event_id: Option<i64>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub creator_id: Option<i64>,
+    #[serde(default)]
+    pub metadata: Option<TogglWebhookMetadata>,
+    /// The changed entity, or "ping" for validation/ping events
+    #[serde(default)]
+    pub payload: serde_json::Value,
+    #[serde(default)]
+    pub subscription_id: Option<i64>,
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    #[serde(default)]
+    pub url_callback: Option<String>,
+    /// Only present on the validation event sent when a subscription is created
+    #[serde(default)]
+    pub validation_code: Option<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// What happened to which kind of entity
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TogglWebhookMetadata {
+    /// "created", "updated" or "deleted"
+    pub action: String,
+    /// "time_entry", "project", "tag", "client", ...
+    pub model: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub request_type: Option<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// Webhook payload parsed according to `metadata.model`
+#[derive(Debug, Clone)]
+pub enum TogglWebhookPayload {
+    TimeEntry(TimeEntry),
+    Project(TogglProject),
+    Tag(Tag),
+    /// Pings and models we don't handle
+    Other(serde_json::Value),
+}
+
+impl TogglWebhookEvent {
+    /// Parse the payload into the struct matching `metadata.model`.
+    /// Falls back to `Other` if the model is unknown or the payload doesn't parse.
+    pub fn typed_payload(&self) -> TogglWebhookPayload {
+        let model = self.metadata.as_ref().map(|m| m.model.as_str()).unwrap_or("");
+        let payload = self.payload.clone();
+        let parsed = match model {
+            "time_entry" => serde_json::from_value(payload).map(TogglWebhookPayload::TimeEntry),
+            "project" => serde_json::from_value(payload).map(TogglWebhookPayload::Project),
+            "tag" => serde_json::from_value(payload).map(TogglWebhookPayload::Tag),
+            _ => return TogglWebhookPayload::Other(self.payload.clone()),
+        };
+        parsed.unwrap_or_else(|err| {
+            println!("[TOGGL] Could not parse {} webhook payload: {}", model, err);
+            TogglWebhookPayload::Other(self.payload.clone())
+        })
+    }
+}
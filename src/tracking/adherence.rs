@@ -0,0 +1,402 @@
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    env,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+use crate::{
+    jobs::error::WebhookError,
+    models::{calendars::TimeBlock, tasks::Task},
+    routes::marvin_webhooks::{marvin_client_from_env, toggl_client_from_env},
+    store::json,
+    toggl_api::{requests::UpdateTimeEntryRequest, responses::TimeEntry},
+    tracking::{days, lock::TRACKING_LOCK},
+};
+
+/// File (inside the data directory) the blocks each entry ran in are kept in.
+const BLOCK_ENTRIES_FILE: &str = "block_entries.json";
+/// File (inside the data directory) the block each entry's task was scheduled into is kept in.
+const PLANNED_ENTRIES_FILE: &str = "planned_entries.json";
+/// Tag put on entries that don't match the active time block
+pub const OFF_PLAN_TAG: &str = "off-plan";
+/// How long fetched time blocks are reused
+const BLOCKS_TTL_SECS: i64 = 60 * 5;
+/// Number of entries remembered in `BLOCK_ENTRIES` and `PLANNED_ENTRIES`
+const HISTORY: usize = 1000;
+
+/// A [start, end) interval
+type Span = (DateTime<Utc>, DateTime<Utc>);
+
+/// Time blocks fetched from Marvin for one day.
+struct FetchedBlocks {
+    date: NaiveDate,
+    fetched_at: DateTime<Utc>,
+    blocks: Vec<TimeBlock>,
+}
+
+/// A time block a Toggl entry ran in, and whether the entry was on plan there.
+/// An entry that runs into the next block gets one of these per block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockEntry {
+    entry_id: i64,
+    block_id: String,
+    on_plan: bool,
+}
+
+/// The Marvin task a Toggl entry tracks and the block that task was scheduled into.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PlannedEntry {
+    entry_id: i64,
+    title: String,
+    time_block_section: Option<String>,
+}
+
+static BLOCK_ENTRIES: LazyLock<Mutex<Vec<BlockEntry>>> =
+    LazyLock::new(|| Mutex::new(json::load_json(BLOCK_ENTRIES_FILE).unwrap_or_default()));
+
+static PLANNED_ENTRIES: LazyLock<Mutex<Vec<PlannedEntry>>> =
+    LazyLock::new(|| Mutex::new(json::load_json(PLANNED_ENTRIES_FILE).unwrap_or_default()));
+
+/// Today's time blocks and when they were fetched.
+static TODAYS_BLOCKS: LazyLock<Mutex<Option<FetchedBlocks>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Whether off-plan entries get tagged in Toggl (`ADHERENCE_TAG_OFF_PLAN`, default false).
+fn tag_off_plan() -> bool {
+    env::var("ADHERENCE_TAG_OFF_PLAN").is_ok_and(|val| val == "true" || val == "1")
+}
+
+/// Whether the running entry is checked at every block start (`ADHERENCE_BLOCK_CHECKS`,
+/// default false). Costs a Marvin request every few minutes.
+fn check_blocks() -> bool {
+    env::var("ADHERENCE_BLOCK_CHECKS").is_ok_and(|val| val == "true" || val == "1")
+}
+
+/// When `block` starts and ends on `date`, or None if it doesn't happen that day.
+/// Recurring blocks are listed for each day they recur on but keep the date of their
+/// first occurrence, so they're moved to `date`; cancelled days are skipped and
+/// exceptions (a moved or shortened occurrence) replace the block's own time.
+/// Block times are local to the user's timezone.
+fn block_span(block: &TimeBlock, date: NaiveDate) -> Option<Span> {
+    let day = date.format("%Y-%m-%d").to_string();
+    if block.cancel_dates.get(&day).copied().unwrap_or(false) {
+        return None;
+    }
+    let exception = block.exceptions.get(&day);
+    let date = match exception.and_then(|exception| exception.date.as_deref()) {
+        Some(moved_to) => NaiveDate::parse_from_str(moved_to, "%Y-%m-%d").ok()?,
+        None if block.recurrence.is_some() => date,
+        None => NaiveDate::parse_from_str(&block.date, "%Y-%m-%d").ok()?,
+    };
+    let time = exception.and_then(|exception| exception.time.as_deref()).unwrap_or(&block.time);
+    let duration = exception
+        .and_then(|exception| exception.duration.as_deref())
+        .unwrap_or(&block.duration);
+    let time = NaiveTime::parse_from_str(time, "%H:%M").ok()?;
+    let minutes: i64 = duration.trim().parse().ok()?;
+    let start = date
+        .and_time(time)
+        .and_local_timezone(days::user_timezone())
+        .earliest()?
+        .with_timezone(&Utc);
+    Some((start, start + ChronoDuration::minutes(minutes)))
+}
+
+async fn blocks_for(date: NaiveDate) -> Result<Vec<TimeBlock>, WebhookError> {
+    let marvin_client = marvin_client_from_env()?;
+    let day = date.format("%Y-%m-%d").to_string();
+    Ok(marvin_client.get_today_time_blocks(Some(&day)).await?)
+}
+
+/// Today's blocks, fetched at most every few minutes.
+async fn todays_blocks() -> Result<Vec<TimeBlock>, WebhookError> {
+    let today = days::day_of(Utc::now());
+    if let Some(fetched) = TODAYS_BLOCKS.lock().unwrap().as_ref()
+        && fetched.date == today
+        && (Utc::now() - fetched.fetched_at).num_seconds() < BLOCKS_TTL_SECS
+    {
+        return Ok(fetched.blocks.clone());
+    }
+    let blocks = blocks_for(today).await?;
+    *TODAYS_BLOCKS.lock().unwrap() = Some(FetchedBlocks {
+        date: today,
+        fetched_at: Utc::now(),
+        blocks: blocks.clone(),
+    });
+    Ok(blocks)
+}
+
+/// Append `entry` to `entries`, keeping the last `HISTORY`, and write them to `file`.
+fn push_capped<T: Serialize>(entries: &mut Vec<T>, entry: T, file: &str) {
+    entries.push(entry);
+    let excess = entries.len().saturating_sub(HISTORY);
+    entries.drain(..excess);
+    if let Err(err) = json::save_json(file, &*entries) {
+        eprintln!("[ADHERENCE] Could not persist {}: {}", file, err);
+    }
+}
+
+/// Record the block `entry` ran in, unless it already was.
+/// Returns whether it's new.
+fn remember(entry: BlockEntry) -> bool {
+    let mut entries = BLOCK_ENTRIES.lock().unwrap();
+    if entries
+        .iter()
+        .any(|known| known.entry_id == entry.entry_id && known.block_id == entry.block_id)
+    {
+        return false;
+    }
+    push_capped(&mut entries, entry, BLOCK_ENTRIES_FILE);
+    true
+}
+
+fn remember_planned(entry: PlannedEntry) {
+    let mut entries = PLANNED_ENTRIES.lock().unwrap();
+    entries.retain(|known| known.entry_id != entry.entry_id);
+    push_capped(&mut entries, entry, PLANNED_ENTRIES_FILE);
+}
+
+fn planned(entry_id: i64) -> Option<PlannedEntry> {
+    PLANNED_ENTRIES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|entry| entry.entry_id == entry_id)
+        .cloned()
+}
+
+/// The block active at `now`, if any.
+async fn active_block(now: DateTime<Utc>) -> Result<Option<(TimeBlock, Span)>, WebhookError> {
+    let today = days::day_of(now);
+    let blocks = todays_blocks().await?;
+    Ok(blocks.into_iter().find_map(|block| {
+        let span = block_span(&block, today)?;
+        (span.0 <= now && now < span.1).then_some((block, span))
+    }))
+}
+
+/// Compare Toggl entry `entry`, just started for `task`, with the active time block.
+/// Entries of tasks scheduled into another block (or none) are off plan.
+pub async fn check_entry(task: &Task, entry: &TimeEntry) -> Result<(), WebhookError> {
+    remember_planned(PlannedEntry {
+        entry_id: entry.id,
+        title: task.title.clone(),
+        time_block_section: task.time_block_section.clone(),
+    });
+    classify(entry).await
+}
+
+/// Check a running entry against the block active now, once per block.
+/// Entries without a known task (nothing was ever tracked for them in Marvin) are skipped.
+async fn classify(entry: &TimeEntry) -> Result<(), WebhookError> {
+    let planned = match planned(entry.id) {
+        Some(planned) => planned,
+        None => return Ok(()),
+    };
+    let block = match active_block(Utc::now()).await? {
+        Some((block, _)) => block,
+        None => return Ok(()),
+    };
+
+    let on_plan = planned.time_block_section.as_deref() == Some(block.id.as_str());
+    let new = remember(BlockEntry {
+        entry_id: entry.id,
+        block_id: block.id.clone(),
+        on_plan,
+    });
+    if !new {
+        return Ok(());
+    }
+    println!(
+        "[ADHERENCE] '{}' during block '{}': {}",
+        planned.title,
+        block.title,
+        if on_plan { "on plan" } else { "off plan" }
+    );
+
+    if !on_plan
+        && tag_off_plan()
+        && let Some(workspace_id) = entry.workspace_id
+    {
+        let toggl_client = toggl_client_from_env()?;
+        let request = UpdateTimeEntryRequest {
+            tag_action: Some("add".to_string()),
+            tags: Some(vec![OFF_PLAN_TAG.to_string()]),
+            ..Default::default()
+        };
+        toggl_client.update_time_entry(workspace_id, entry.id, &request).await?;
+    }
+    Ok(())
+}
+
+/// Compare an entry started outside marvinhooks (found by reverse sync) with the
+/// active time block, using the Marvin task it was matched with.
+pub async fn check_foreign_entry(entry: &TimeEntry, task_id: &str) -> Result<(), WebhookError> {
+    let marvin_client = marvin_client_from_env()?;
+    let task = marvin_client.read_doc_as::<Task>(task_id).await?.doc;
+    check_entry(&task, entry).await
+}
+
+/// Check whatever is running whenever a time block starts, so entries that run from
+/// one block into the next are judged against each. Blocks are looked up again every
+/// few minutes to pick up changes and the next day.
+/// Does nothing unless `ADHERENCE_BLOCK_CHECKS` is set and Marvin is configured.
+pub async fn run_block_checks() {
+    if !check_blocks() {
+        return;
+    }
+    if let Err(err) = marvin_client_from_env() {
+        println!("[ADHERENCE] Block checks disabled: {}", err);
+        return;
+    }
+    println!("[ADHERENCE] Checking the running entry at every block start");
+
+    loop {
+        let now = Utc::now();
+        let next_start = match todays_blocks().await {
+            Ok(blocks) => {
+                let today = days::day_of(now);
+                blocks
+                    .iter()
+                    .filter_map(|block| block_span(block, today))
+                    .map(|(start, _)| start)
+                    .filter(|start| *start > now)
+                    .min()
+            }
+            Err(err) => {
+                println!("[ADHERENCE] Could not fetch time blocks: {}", err.chain().join(": "));
+                None
+            }
+        };
+        let wait = next_start
+            .map(|start| (start - now).num_seconds() + 1)
+            .unwrap_or(BLOCKS_TTL_SECS)
+            .clamp(1, BLOCKS_TTL_SECS);
+        sleep(Duration::from_secs(wait as u64)).await;
+
+        if next_start.is_none_or(|start| Utc::now() < start) {
+            continue;
+        }
+        let toggl_client = match toggl_client_from_env() {
+            Ok(client) => client,
+            Err(err) => {
+                println!("[ADHERENCE] {}", err);
+                continue;
+            }
+        };
+        // The off-plan tag must not race a stop of the same entry
+        let _guard = TRACKING_LOCK.lock().await;
+        match toggl_client.get_current_time_entry().await {
+            Ok(Some(entry)) => {
+                if let Err(err) = classify(&entry).await {
+                    println!("[ADHERENCE] Time block check failed: {}", err.chain().join(": "));
+                }
+            }
+            Ok(None) => {}
+            Err(err) => println!("[ADHERENCE] Could not fetch current entry: {}", err),
+        }
+    }
+}
+
+/// Keep the block of an entry that was continued in a new one (e.g. pomodoro splits).
+pub fn follow_entry(old_entry_id: i64, new_entry_id: i64) {
+    let previous: Vec<BlockEntry> = BLOCK_ENTRIES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry.entry_id == old_entry_id)
+        .cloned()
+        .collect();
+    for previous in previous {
+        remember(BlockEntry { entry_id: new_entry_id, ..previous });
+    }
+    if let Some(previous) = planned(old_entry_id) {
+        remember_planned(PlannedEntry { entry_id: new_entry_id, ..previous });
+    }
+}
+
+/// Planned versus actual time for one time block.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockAdherence {
+    pub block_id: String,
+    pub title: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub planned_minutes: i64,
+    /// Everything tracked during the block
+    pub tracked_minutes: i64,
+    /// Time tracked on tasks scheduled into the block
+    pub on_plan_minutes: i64,
+    pub off_plan_minutes: i64,
+}
+
+/// GET /adherence body
+#[derive(Debug, Clone, Serialize)]
+pub struct AdherenceReport {
+    pub date: NaiveDate,
+    pub blocks: Vec<BlockAdherence>,
+}
+
+fn overlap_minutes(a: Span, b: Span) -> i64 {
+    (a.1.min(b.1) - a.0.max(b.0)).num_seconds().max(0) / 60
+}
+
+/// Planned versus actual minutes for every time block on `date`.
+pub async fn adherence_report(date: NaiveDate) -> Result<AdherenceReport, WebhookError> {
+    let blocks = blocks_for(date).await?;
+
+    let toggl_client = toggl_client_from_env()?;
+    let entries = toggl_client
+        .list_time_entries(
+            &(date - ChronoDuration::days(1)).format("%Y-%m-%d").to_string(),
+            &(date + ChronoDuration::days(2)).format("%Y-%m-%d").to_string(),
+        )
+        .await?;
+    let spans: Vec<(i64, Span)> = entries
+        .iter()
+        .filter_map(|entry| {
+            let start = entry.start.parse().ok()?;
+            let stop = match &entry.stop {
+                Some(stop) => stop.parse().ok()?,
+                None => Utc::now(),
+            };
+            Some((entry.id, (start, stop)))
+        })
+        .collect();
+    let on_plan: HashSet<(i64, String)> = BLOCK_ENTRIES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry.on_plan)
+        .map(|entry| (entry.entry_id, entry.block_id.clone()))
+        .collect();
+
+    let mut report = AdherenceReport { date, blocks: vec![] };
+    for block in &blocks {
+        let span = match block_span(block, date) {
+            Some(span) => span,
+            None => continue,
+        };
+        let tracked_minutes = spans.iter().map(|(_, entry)| overlap_minutes(span, *entry)).sum();
+        let on_plan_minutes = spans
+            .iter()
+            .filter(|(id, _)| on_plan.contains(&(*id, block.id.clone())))
+            .map(|(_, entry)| overlap_minutes(span, *entry))
+            .sum();
+        report.blocks.push(BlockAdherence {
+            block_id: block.id.clone(),
+            title: block.title.clone(),
+            start: span.0,
+            end: span.1,
+            planned_minutes: (span.1 - span.0).num_minutes(),
+            tracked_minutes,
+            on_plan_minutes,
+            off_plan_minutes: tracked_minutes - on_plan_minutes,
+        });
+    }
+    report.blocks.sort_by_key(|block| block.start);
+    Ok(report)
+}
@@ -0,0 +1,154 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+
+use crate::USER_TIMEZONE;
+
+/// The Toggl user's timezone, which decides where days begin. UTC until it's known.
+pub fn user_timezone() -> Tz {
+    USER_TIMEZONE.get().copied().unwrap_or(Tz::UTC)
+}
+
+/// The day `time` falls on in the user's timezone.
+pub fn day_of(time: DateTime<Utc>) -> NaiveDate {
+    day_in(time, user_timezone())
+}
+
+fn day_in(time: DateTime<Utc>, tz: Tz) -> NaiveDate {
+    time.with_timezone(&tz).date_naive()
+}
+
+/// Start of the day after `day` in `tz`, in UTC.
+fn next_midnight(day: NaiveDate, tz: Tz) -> DateTime<Utc> {
+    let next_day = day.succ_opt().unwrap_or(day);
+    let midnight = next_day.and_hms_opt(0, 0, 0).unwrap();
+    match midnight.and_local_timezone(tz).earliest() {
+        Some(midnight) => midnight.with_timezone(&Utc),
+        // Midnight skipped by a DST change; the day starts an hour later
+        None => next_day
+            .and_hms_opt(1, 0, 0)
+            .unwrap()
+            .and_local_timezone(tz)
+            .earliest()
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or_else(|| midnight.and_utc()),
+    }
+}
+
+/// Split [start, stop) at every midnight in the user's timezone.
+/// Returns one (day, start, stop) segment per day, in order.
+pub fn day_segments(
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) -> Vec<(NaiveDate, DateTime<Utc>, DateTime<Utc>)> {
+    segments_in(start, stop, user_timezone())
+}
+
+fn segments_in(
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    tz: Tz,
+) -> Vec<(NaiveDate, DateTime<Utc>, DateTime<Utc>)> {
+    let mut segments = vec![];
+    let mut segment_start = start;
+    while segment_start < stop {
+        let day = day_in(segment_start, tz);
+        let mut segment_stop = next_midnight(day, tz).min(stop);
+        if segment_stop <= segment_start {
+            segment_stop = stop;
+        }
+        segments.push((day, segment_start, segment_stop));
+        segment_start = segment_stop;
+    }
+    if segments.is_empty() {
+        segments.push((day_in(start, tz), start, stop));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use chrono_tz::{America::Santiago, Europe::Berlin};
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    fn date(text: &str) -> NaiveDate {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn keeps_an_entry_within_one_day() {
+        let (start, stop) = (utc("2026-05-04T08:00:00Z"), utc("2026-05-04T09:30:00Z"));
+        assert_eq!(segments_in(start, stop, Berlin), vec![(date("2026-05-04"), start, stop)]);
+    }
+
+    #[test]
+    fn entry_ending_at_midnight_stays_on_its_day() {
+        // 2026-05-04 22:00 UTC is midnight in Berlin (UTC+2)
+        let (start, stop) = (utc("2026-05-04T20:00:00Z"), utc("2026-05-04T22:00:00Z"));
+        assert_eq!(segments_in(start, stop, Berlin), vec![(date("2026-05-04"), start, stop)]);
+    }
+
+    #[test]
+    fn entry_starting_at_midnight_is_on_the_new_day() {
+        let (start, stop) = (utc("2026-05-04T22:00:00Z"), utc("2026-05-04T23:00:00Z"));
+        assert_eq!(segments_in(start, stop, Berlin), vec![(date("2026-05-05"), start, stop)]);
+    }
+
+    #[test]
+    fn splits_a_multi_day_entry_at_each_midnight() {
+        let (start, stop) = (utc("2026-05-04T20:00:00Z"), utc("2026-05-07T02:00:00Z"));
+        let midnights = [
+            utc("2026-05-04T22:00:00Z"),
+            utc("2026-05-05T22:00:00Z"),
+            utc("2026-05-06T22:00:00Z"),
+        ];
+        assert_eq!(
+            segments_in(start, stop, Berlin),
+            vec![
+                (date("2026-05-04"), start, midnights[0]),
+                (date("2026-05-05"), midnights[0], midnights[1]),
+                (date("2026-05-06"), midnights[1], midnights[2]),
+                (date("2026-05-07"), midnights[2], stop),
+            ]
+        );
+    }
+
+    #[test]
+    fn dst_days_are_shorter_or_longer() {
+        // Clocks go forward on 2026-03-29 and back on 2026-10-25 in Berlin
+        let spring = segments_in(utc("2026-03-28T23:00:00Z"), utc("2026-03-29T22:00:00Z"), Berlin);
+        assert_eq!(spring.len(), 1);
+        assert_eq!(spring[0].0, date("2026-03-29"));
+        assert_eq!(next_midnight(date("2026-03-29"), Berlin), utc("2026-03-29T22:00:00Z"));
+
+        let fall = segments_in(utc("2026-10-24T22:00:00Z"), utc("2026-10-26T00:00:00Z"), Berlin);
+        assert_eq!(
+            fall,
+            vec![
+                (date("2026-10-25"), utc("2026-10-24T22:00:00Z"), utc("2026-10-25T23:00:00Z")),
+                (date("2026-10-26"), utc("2026-10-25T23:00:00Z"), utc("2026-10-26T00:00:00Z")),
+            ]
+        );
+    }
+
+    #[test]
+    fn day_starts_at_one_when_dst_skips_midnight() {
+        // Santiago skips from 00:00 to 01:00 on 2026-09-06
+        let day_start = Santiago
+            .with_ymd_and_hms(2026, 9, 6, 1, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(next_midnight(date("2026-09-05"), Santiago), day_start);
+
+        let hour = chrono::Duration::hours(1);
+        let (start, stop) = (day_start - hour, day_start + hour);
+        assert_eq!(
+            segments_in(start, stop, Santiago),
+            vec![(date("2026-09-05"), start, day_start), (date("2026-09-06"), day_start, stop)]
+        );
+    }
+}
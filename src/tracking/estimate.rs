@@ -0,0 +1,169 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    env,
+    sync::{LazyLock, Mutex},
+};
+use tokio::time::sleep;
+
+use crate::{
+    api::requests::SetRemindersRequest,
+    jobs::error::WebhookError,
+    models::{reminders::Reminder, tasks::Task},
+    routes::marvin_webhooks::{marvin_client_from_env, toggl_client_from_env},
+    toggl_api::requests::UpdateTimeEntryRequest,
+    tracking::lock::TRACKING_LOCK,
+};
+
+/// Tag put on Toggl entries that ran past their task's estimate
+pub const OVER_ESTIMATE_TAG: &str = "over-estimate";
+
+/// The running entry of a Marvin task with a time estimate.
+#[derive(Debug, Clone, Serialize)]
+pub struct EstimateWatch {
+    pub task_id: String,
+    pub title: String,
+    pub entry_id: i64,
+    /// Milliseconds, like Marvin's `timeEstimate`
+    pub estimate_ms: i64,
+    /// Time tracked on the task before this entry started
+    pub tracked_before_ms: i64,
+    pub started_at: DateTime<Utc>,
+    /// When the estimate runs out
+    pub due_at: DateTime<Utc>,
+    /// Whether we already warned about this entry
+    pub warned: bool,
+}
+
+/// GET /estimate body
+#[derive(Debug, Clone, Serialize)]
+pub struct EstimateStatus {
+    #[serde(flatten)]
+    pub watch: EstimateWatch,
+    pub tracked_ms: i64,
+    /// How far past the estimate the task is (0 while within it)
+    pub overrun_ms: i64,
+}
+
+static WATCH: LazyLock<Mutex<Option<EstimateWatch>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Whether over-estimate entries get tagged in Toggl (`OVER_ESTIMATE_TAG_ENTRIES`, default false).
+fn tag_entries() -> bool {
+    env::var("OVER_ESTIMATE_TAG_ENTRIES").is_ok_and(|val| val == "true" || val == "1")
+}
+
+/// Start watching Toggl entry `entry_id`, just started for `task`, against the
+/// task's time estimate. Replaces whatever was watched before.
+pub fn watch_entry(task: &Task, entry_id: i64) {
+    let estimate_ms = match task.time_estimate {
+        Some(estimate_ms) if estimate_ms > 0 => estimate_ms,
+        _ => {
+            *WATCH.lock().unwrap() = None;
+            return;
+        }
+    };
+    let tracked_before_ms = task.duration.unwrap_or(0);
+    let started_at = Utc::now();
+    let remaining_ms = (estimate_ms - tracked_before_ms).max(0);
+    let watch = EstimateWatch {
+        task_id: task.id.clone(),
+        title: task.title.clone(),
+        entry_id,
+        estimate_ms,
+        tracked_before_ms,
+        started_at,
+        due_at: started_at + chrono::Duration::milliseconds(remaining_ms),
+        warned: false,
+    };
+    println!("[ESTIMATE] Watching '{}' (entry {}), estimate runs out at {}", watch.title, entry_id, watch.due_at);
+    *WATCH.lock().unwrap() = Some(watch);
+
+    tokio::spawn(async move {
+        sleep(std::time::Duration::from_millis(remaining_ms as u64)).await;
+        let _guard = TRACKING_LOCK.lock().await;
+        if let Err(err) = check_estimate(started_at).await {
+            println!("[ESTIMATE] Check failed: {}", err.chain().join(": "));
+        }
+    });
+}
+
+/// Warn if the watch that started at `started_at` is still active and its entry still running.
+/// Callers must hold the tracking lock.
+async fn check_estimate(started_at: DateTime<Utc>) -> Result<(), WebhookError> {
+    let watch = match WATCH.lock().unwrap().clone() {
+        Some(watch) if watch.started_at == started_at && !watch.warned => watch,
+        _ => return Ok(()),
+    };
+    let entry_id = watch.entry_id;
+
+    let toggl_client = toggl_client_from_env()?;
+    let current = match toggl_client.get_current_time_entry().await? {
+        Some(current) if current.id == entry_id => current,
+        _ => {
+            *WATCH.lock().unwrap() = None;
+            return Ok(());
+        }
+    };
+
+    println!("[ESTIMATE] '{}' is over its estimate", watch.title);
+    let marvin_client = marvin_client_from_env()?;
+    let request = SetRemindersRequest {
+        reminders: vec![Reminder {
+            time: Utc::now().timestamp(),
+            offset: 0,
+            reminder_id: format!("marvinhooks-over-estimate-{}", entry_id),
+            reminder_type: "M".to_string(),
+            title: Some(format!(
+                "'{}' is over its {} minute estimate",
+                watch.title,
+                watch.estimate_ms / 60_000
+            )),
+            snooze: None,
+            auto_snooze: None,
+            can_track: true,
+        }],
+    };
+    marvin_client.set_reminders(&request).await?;
+
+    if tag_entries()
+        && let Some(workspace_id) = current.workspace_id
+    {
+        let request = UpdateTimeEntryRequest {
+            tag_action: Some("add".to_string()),
+            tags: Some(vec![OVER_ESTIMATE_TAG.to_string()]),
+            ..Default::default()
+        };
+        toggl_client.update_time_entry(workspace_id, entry_id, &request).await?;
+    }
+
+    if let Some(watch) = WATCH.lock().unwrap().as_mut().filter(|watch| watch.entry_id == entry_id) {
+        watch.warned = true;
+    }
+    Ok(())
+}
+
+/// Keep watching after the running entry was continued in a new one (e.g. pomodoro splits).
+pub fn follow_entry(old_entry_id: i64, new_entry_id: i64) {
+    if let Some(watch) = WATCH.lock().unwrap().as_mut().filter(|watch| watch.entry_id == old_entry_id) {
+        watch.entry_id = new_entry_id;
+    }
+}
+
+/// Stop watching, e.g. because the watched task was stopped.
+pub fn clear_watch(task_id: &str) {
+    let mut watch = WATCH.lock().unwrap();
+    if watch.as_ref().is_some_and(|watch| watch.task_id == task_id) {
+        *watch = None;
+    }
+}
+
+/// The watched entry and how it's doing against the estimate.
+pub fn status() -> Option<EstimateStatus> {
+    let watch = WATCH.lock().unwrap().clone()?;
+    let tracked_ms = watch.tracked_before_ms + (Utc::now() - watch.started_at).num_milliseconds();
+    Some(EstimateStatus {
+        overrun_ms: (tracked_ms - watch.estimate_ms).max(0),
+        tracked_ms,
+        watch,
+    })
+}
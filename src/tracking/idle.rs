@@ -0,0 +1,102 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use serde::Serialize;
+use std::{
+    env,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+use crate::{
+    jobs::error::WebhookError, routes::marvin_webhooks::toggl_client_from_env,
+    tracking::lock::TRACKING_LOCK,
+};
+
+/// Default time between idle checks
+const DEFAULT_CHECK_INTERVAL_SECS: u64 = 60;
+
+/// The last sign of life from a heartbeat agent.
+#[derive(Debug, Clone, Serialize)]
+pub struct Heartbeat {
+    pub at: DateTime<Utc>,
+    /// Which agent sent it, e.g. "desktop" or "browser"
+    pub source: Option<String>,
+}
+
+static LAST_HEARTBEAT: LazyLock<Mutex<Option<Heartbeat>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Remember that the user was active just now.
+pub fn record_heartbeat(source: Option<String>) {
+    *LAST_HEARTBEAT.lock().unwrap() = Some(Heartbeat { at: Utc::now(), source });
+}
+
+pub fn last_heartbeat() -> Option<Heartbeat> {
+    LAST_HEARTBEAT.lock().unwrap().clone()
+}
+
+/// Stop the running entry as of `last_active` if it was already running then.
+/// Entries started after the last heartbeat were started somewhere else (e.g. a
+/// phone) and are left alone. Callers must hold the tracking lock.
+async fn stop_idle_entry(last_active: DateTime<Utc>) -> Result<(), WebhookError> {
+    let toggl_client = toggl_client_from_env()?;
+    let entry = match toggl_client.get_current_time_entry().await? {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    let started_before = entry
+        .start
+        .parse::<DateTime<Utc>>()
+        .is_ok_and(|start| start < last_active);
+    if !started_before {
+        return Ok(());
+    }
+
+    println!("[IDLE] No heartbeat since {}, stopping entry {} there", last_active, entry.id);
+    let stopped = toggl_client.stop_time_entry_at(&entry, last_active, None).await?;
+    println!("[IDLE] Entry {} stopped, leisure {:+}ms", entry.id, stopped.leisure_change);
+    Ok(())
+}
+
+/// Stop entries that keep running after heartbeats stopped coming in for
+/// `IDLE_TIMEOUT_MINUTES`, back-dated to the last heartbeat.
+/// Does nothing if the variable isn't set, and nothing until the first heartbeat arrives.
+pub async fn run_idle_detection() {
+    let timeout_minutes: i64 = match env::var("IDLE_TIMEOUT_MINUTES") {
+        Ok(val) => match val.parse() {
+            Ok(minutes) if minutes > 0 => minutes,
+            _ => {
+                eprintln!("IDLE_TIMEOUT_MINUTES is not a positive number, idle detection disabled");
+                return;
+            }
+        },
+        Err(_) => return,
+    };
+    let interval_secs = match env::var("IDLE_CHECK_INTERVAL_SECS") {
+        Ok(val) => val.parse().unwrap_or_else(|_| {
+            eprintln!("IDLE_CHECK_INTERVAL_SECS is not a number, using the default");
+            DEFAULT_CHECK_INTERVAL_SECS
+        }),
+        Err(_) => DEFAULT_CHECK_INTERVAL_SECS,
+    };
+    println!("[IDLE] Stopping entries after {} minutes without a heartbeat", timeout_minutes);
+
+    // Heartbeat we already acted on, so an idle stretch is only handled once
+    let mut handled: Option<DateTime<Utc>> = None;
+    loop {
+        sleep(Duration::from_secs(interval_secs)).await;
+
+        let last_active = match last_heartbeat() {
+            Some(heartbeat) => heartbeat.at,
+            None => continue,
+        };
+        if handled == Some(last_active) || Utc::now() - last_active < ChronoDuration::minutes(timeout_minutes) {
+            continue;
+        }
+
+        let _guard = TRACKING_LOCK.lock().await;
+        match stop_idle_entry(last_active).await {
+            Ok(()) => handled = Some(last_active),
+            Err(err) => println!("[IDLE] Check failed: {}", err.chain().join(": ")),
+        }
+    }
+}
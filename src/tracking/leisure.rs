@@ -0,0 +1,109 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use std::{
+    collections::BTreeMap,
+    sync::{LazyLock, Mutex, atomic::Ordering},
+};
+
+use crate::{
+    LEISURE_BALANCE, LEISURE_RATE,
+    tracking::{days, pomodoro},
+};
+
+/// Leisure earned (or spent) per day in the user's timezone, in milliseconds.
+static LEISURE_BY_DAY: LazyLock<Mutex<BTreeMap<NaiveDate, i64>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Leisure change for `duration_ms` of tracked time, without applying it.
+fn leisure_change(
+    tags: Option<&[String]>,
+    productivity_override: Option<bool>,
+    duration_ms: i64,
+) -> i64 {
+    if pomodoro::is_exempt_break(tags) {
+        println!("[LEISURE] {}ms of pomodoro break, balance unchanged", duration_ms);
+        return 0;
+    }
+
+    let rate = *LEISURE_RATE.lock().unwrap();
+    let earned = (rate * duration_ms as f64) as i64;
+    let spent = -duration_ms;
+
+    match productivity_override {
+        None => tags
+            .unwrap_or_default()
+            .iter()
+            .map(|tag| match tag.as_str() {
+                "productive" => earned,
+                "unproductive" => spent,
+                _ => 0,
+            })
+            .sum(),
+        Some(true) => earned,
+        Some(false) => spent,
+    }
+}
+
+/// Add `change` to the balance and to the leisure of `day`.
+fn credit(day: NaiveDate, change: i64) {
+    if change == 0 {
+        return;
+    }
+    LEISURE_BALANCE.fetch_add(change, Ordering::SeqCst);
+    *LEISURE_BY_DAY.lock().unwrap().entry(day).or_insert(0) += change;
+}
+
+/// Add `change` (ms) to the balance, counting it towards today.
+pub fn credit_leisure(change: i64) {
+    credit(days::day_of(Utc::now()), change);
+}
+
+/// Update the leisure balance for an entry that ran from `start` to `stop`:
+/// productive time earns leisure at the current rate, unproductive time spends it
+/// one to one, and neutral (untagged) time doesn't count.
+/// `productivity_override` takes precedence over the entry's tags.
+/// Pomodoro breaks are skipped if they're configured to be exempt.
+/// Each day the entry touched gets the leisure for its part of the entry.
+/// Returns the total change, in milliseconds.
+pub fn accrue_leisure_between(
+    tags: Option<&[String]>,
+    productivity_override: Option<bool>,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) -> i64 {
+    credit_between(tags, productivity_override, start, stop, 1)
+}
+
+/// Take back what `accrue_leisure_between` credited for the same entry, day by day.
+pub fn revert_leisure_between(
+    tags: Option<&[String]>,
+    productivity_override: Option<bool>,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) -> i64 {
+    credit_between(tags, productivity_override, start, stop, -1)
+}
+
+fn credit_between(
+    tags: Option<&[String]>,
+    productivity_override: Option<bool>,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    sign: i64,
+) -> i64 {
+    let mut total = 0;
+    for (day, segment_start, segment_stop) in days::day_segments(start, stop) {
+        let duration_ms = (segment_stop - segment_start).num_milliseconds();
+        let change = sign * leisure_change(tags, productivity_override, duration_ms);
+        if change != 0 {
+            credit(day, change);
+            println!("[LEISURE] {}ms tracked on {} -> balance {:+}ms", sign * duration_ms, day, change);
+        }
+        total += change;
+    }
+    total
+}
+
+/// Leisure per day, oldest first.
+pub fn leisure_by_day() -> BTreeMap<NaiveDate, i64> {
+    LEISURE_BY_DAY.lock().unwrap().clone()
+}
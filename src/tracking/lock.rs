@@ -0,0 +1,9 @@
+use std::sync::LazyLock;
+use tokio::sync::Mutex;
+
+/// Guards the Toggl tracking state machine: looking at the running entry,
+/// stopping it, accruing leisure and starting the next one.
+/// Anything that does more than a single read must hold this for the whole
+/// sequence, otherwise e.g. a quick stop-then-start can stop the wrong entry
+/// or count leisure twice.
+pub static TRACKING_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
@@ -0,0 +1,10 @@
+pub mod adherence;
+pub mod days;
+pub mod estimate;
+pub mod idle;
+pub mod leisure;
+pub mod lock;
+pub mod policy;
+pub mod pomodoro;
+pub mod resume;
+pub mod watchdog;
@@ -0,0 +1,163 @@
+use serde::Deserialize;
+use std::{collections::HashMap, env, sync::LazyLock};
+
+/// How rounded durations are chosen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    #[default]
+    Nearest,
+    Up,
+    Down,
+}
+
+/// What happens to entries marvinhooks stops.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct EntryPolicy {
+    /// Entries shorter than this are deleted
+    #[serde(default)]
+    pub min_secs: i64,
+    /// Durations are rounded to a multiple of this (0 = no rounding)
+    #[serde(default)]
+    pub round_secs: i64,
+    #[serde(default)]
+    pub rounding: Rounding,
+}
+
+/// What to do with an entry that ran for a given time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyOutcome {
+    Delete,
+    Keep { duration_ms: i64 },
+}
+
+impl EntryPolicy {
+    pub fn apply(&self, duration_ms: i64) -> PolicyOutcome {
+        if duration_ms < self.min_secs * 1000 {
+            return PolicyOutcome::Delete;
+        }
+        if self.round_secs <= 0 {
+            return PolicyOutcome::Keep { duration_ms };
+        }
+
+        let increment = self.round_secs * 1000;
+        let rounded = match self.rounding {
+            Rounding::Nearest => (duration_ms + increment / 2) / increment * increment,
+            Rounding::Up => (duration_ms + increment - 1) / increment * increment,
+            Rounding::Down => duration_ms / increment * increment,
+        };
+        // Rounded down to nothing: only `min_secs` deletes entries, so keep it as it is
+        if rounded <= 0 {
+            return PolicyOutcome::Keep { duration_ms };
+        }
+        PolicyOutcome::Keep { duration_ms: rounded }
+    }
+}
+
+/// The default policy from `ENTRY_MIN_SECS`, `ENTRY_ROUND_SECS` and `ENTRY_ROUNDING`
+/// (nearest, up or down), and per-project overrides from `ENTRY_POLICY_OVERRIDES`,
+/// a JSON object of Toggl project ID -> policy, e.g.
+/// {"123": {"min_secs": 60, "round_secs": 900, "rounding": "up"}}
+struct Policies {
+    default: EntryPolicy,
+    overrides: HashMap<i64, EntryPolicy>,
+}
+
+static POLICIES: LazyLock<Policies> = LazyLock::new(|| {
+    let number = |name: &str| match env::var(name) {
+        Ok(val) => val.parse().unwrap_or_else(|_| {
+            eprintln!("{} is not a number, ignoring it", name);
+            0
+        }),
+        Err(_) => 0,
+    };
+    let rounding = match env::var("ENTRY_ROUNDING").as_deref() {
+        Ok("up") => Rounding::Up,
+        Ok("down") => Rounding::Down,
+        _ => Rounding::Nearest,
+    };
+    let default = EntryPolicy {
+        min_secs: number("ENTRY_MIN_SECS"),
+        round_secs: number("ENTRY_ROUND_SECS"),
+        rounding,
+    };
+
+    let overrides = match env::var("ENTRY_POLICY_OVERRIDES") {
+        Ok(val) => match serde_json::from_str::<HashMap<String, EntryPolicy>>(&val) {
+            Ok(overrides) => overrides
+                .into_iter()
+                .filter_map(|(project_id, policy)| Some((project_id.parse().ok()?, policy)))
+                .collect(),
+            Err(err) => {
+                eprintln!("ENTRY_POLICY_OVERRIDES is not valid JSON: {}", err);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    };
+
+    Policies { default, overrides }
+});
+
+/// The policy for entries in Toggl project `project_id`.
+pub fn policy_for(project_id: Option<i64>) -> EntryPolicy {
+    project_id
+        .and_then(|project_id| POLICIES.overrides.get(&project_id).copied())
+        .unwrap_or(POLICIES.default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(min_secs: i64, round_secs: i64, rounding: Rounding) -> EntryPolicy {
+        EntryPolicy { min_secs, round_secs, rounding }
+    }
+
+    #[test]
+    fn deletes_entries_below_the_minimum() {
+        let policy = policy(60, 0, Rounding::Nearest);
+        assert_eq!(policy.apply(59_999), PolicyOutcome::Delete);
+        assert_eq!(policy.apply(60_000), PolicyOutcome::Keep { duration_ms: 60_000 });
+    }
+
+    #[test]
+    fn keeps_the_duration_without_rounding() {
+        for round_secs in [0, -1] {
+            let policy = policy(0, round_secs, Rounding::Up);
+            assert_eq!(policy.apply(1_234), PolicyOutcome::Keep { duration_ms: 1_234 });
+        }
+    }
+
+    #[test]
+    fn rounds_up() {
+        let policy = policy(0, 900, Rounding::Up);
+        assert_eq!(policy.apply(1), PolicyOutcome::Keep { duration_ms: 900_000 });
+        assert_eq!(policy.apply(900_000), PolicyOutcome::Keep { duration_ms: 900_000 });
+        assert_eq!(policy.apply(900_001), PolicyOutcome::Keep { duration_ms: 1_800_000 });
+    }
+
+    #[test]
+    fn rounds_down() {
+        let policy = policy(0, 900, Rounding::Down);
+        assert_eq!(policy.apply(1_799_999), PolicyOutcome::Keep { duration_ms: 900_000 });
+        assert_eq!(policy.apply(899_999), PolicyOutcome::Keep { duration_ms: 899_999 });
+    }
+
+    #[test]
+    fn rounds_to_nearest() {
+        let policy = policy(0, 900, Rounding::Nearest);
+        assert_eq!(policy.apply(1_349_999), PolicyOutcome::Keep { duration_ms: 900_000 });
+        assert_eq!(policy.apply(1_350_000), PolicyOutcome::Keep { duration_ms: 1_800_000 });
+        assert_eq!(policy.apply(449_999), PolicyOutcome::Keep { duration_ms: 449_999 });
+    }
+
+    #[test]
+    fn rounding_never_deletes() {
+        let policy = policy(0, 900, Rounding::Down);
+        assert_eq!(policy.apply(600_000), PolicyOutcome::Keep { duration_ms: 600_000 });
+        let policy = EntryPolicy { min_secs: 60, ..policy };
+        assert_eq!(policy.apply(59_999), PolicyOutcome::Delete);
+        assert_eq!(policy.apply(60_000), PolicyOutcome::Keep { duration_ms: 60_000 });
+    }
+}
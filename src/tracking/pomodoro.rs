@@ -0,0 +1,193 @@
+use chrono::{DateTime, Utc};
+use std::{
+    env,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::{
+    jobs::error::WebhookError,
+    models::timers::TomatoTimer,
+    routes::marvin_webhooks::{toggl_client_from_env, workspace_id},
+    sync::origin,
+    toggl_api::{client::StopCondition, requests::CreateTimeEntryRequest},
+    tracking::{adherence, estimate, leisure::credit_leisure},
+};
+
+/// Tag put on Toggl entries that cover a pomodoro break
+pub const BREAK_TAG: &str = "pomodoro-break";
+
+/// A single work or break phase of a tomato timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Phase {
+    timer_id: Option<i64>,
+    cycle: i64,
+    is_work: bool,
+}
+
+impl Phase {
+    fn of(timer: &TomatoTimer) -> Self {
+        Phase {
+            timer_id: timer.timer_id,
+            cycle: timer.cycle,
+            is_work: timer.is_work,
+        }
+    }
+}
+
+/// Slack for a work phase that's reported done slightly before its full duration
+const COMPLETION_TOLERANCE_MS: i64 = 5000;
+
+/// A phase and when Marvin first reported it.
+#[derive(Debug, Clone, Copy)]
+struct CurrentPhase {
+    phase: Phase,
+    started_at: DateTime<Utc>,
+}
+
+/// The phase Marvin last reported, or None if no tomato timer is running.
+static CURRENT_PHASE: LazyLock<Mutex<Option<CurrentPhase>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Whether time spent on breaks is left out of the leisure balance
+/// (`POMODORO_BREAK_EXEMPT`, default true).
+fn breaks_exempt() -> bool {
+    match env::var("POMODORO_BREAK_EXEMPT") {
+        Ok(val) => val != "false" && val != "0",
+        Err(_) => true,
+    }
+}
+
+/// Leisure (ms) earned for each completed work phase (`POMODORO_BONUS_SECS`, default 0).
+fn completion_bonus_ms() -> i64 {
+    match env::var("POMODORO_BONUS_SECS") {
+        Ok(val) => match val.parse::<i64>() {
+            Ok(secs) => secs * 1000,
+            Err(_) => {
+                eprintln!("POMODORO_BONUS_SECS is not a number, no bonus awarded");
+                0
+            }
+        },
+        Err(_) => 0,
+    }
+}
+
+/// Whether an entry with `tags` is a pomodoro break that shouldn't affect leisure.
+pub fn is_exempt_break(tags: Option<&[String]>) -> bool {
+    tags.unwrap_or_default().iter().any(|tag| tag == BREAK_TAG) && breaks_exempt()
+}
+
+/// Stop the running Toggl entry and continue it in a new one, tagged as a break
+/// or not depending on `on_break`. Nothing happens if the running entry is
+/// already in the right state.
+async fn split_current_entry(on_break: bool) -> Result<String, WebhookError> {
+    let toggl_client = toggl_client_from_env()?;
+    let current = match toggl_client.get_current_time_entry().await? {
+        Some(current) => current,
+        None => return Ok("Nothing running".to_string()),
+    };
+
+    let mut tags: Vec<String> = current
+        .tags
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|tag| tag != BREAK_TAG)
+        .collect();
+    let was_break = tags.len() != current.tags.as_ref().map_or(0, Vec::len);
+    if was_break == on_break {
+        return Ok("Entry already matches the phase".to_string());
+    }
+    if on_break {
+        tags.push(BREAK_TAG.to_string());
+    }
+
+    // Leisure for the finished phase is accounted for when it's stopped
+    toggl_client
+        .stop_current_time_entry(None, StopCondition::Always)
+        .await?;
+
+    let workspace_id = match current.workspace_id {
+        Some(workspace_id) => workspace_id,
+        None => workspace_id()?,
+    };
+    let body = CreateTimeEntryRequest {
+        billable: current.billable,
+        created_with: "MarvinWebhook".to_string(),
+        description: current.description.clone(),
+        duration: -1,
+        duronly: None,
+        event_metadata: None,
+        pid: None,
+        project_id: current.project_id,
+        shared_with_user_ids: None,
+        start: chrono::Utc::now().to_rfc3339(),
+        start_date: None,
+        stop: None,
+        tag_action: None,
+        tag_ids: None,
+        tags: Some(tags),
+        task_id: current.task_id,
+        tid: None,
+        user_id: None,
+        workspace_id,
+    };
+    let entry = toggl_client.create_time_entry(workspace_id, &body).await?;
+    // The continuation belongs to whoever started the original entry
+    if origin::is_own_entry(current.id) {
+        origin::mark_own_entry(entry.id);
+    } else {
+        origin::mark_running(entry.id);
+    }
+    estimate::follow_entry(current.id, entry.id);
+    adherence::follow_entry(current.id, entry.id);
+
+    println!(
+        "[POMODORO] Split entry {} into {} ({})",
+        current.id,
+        entry.id,
+        if on_break { "break" } else { "work" }
+    );
+    Ok(format!("Continued in entry {}", entry.id))
+}
+
+/// Follow a tomato timer reported by Marvin: split the running Toggl entry at
+/// every work/break boundary and award the completion bonus for finished work phases.
+/// Called by the job worker, which holds the tracking lock.
+pub async fn process_tomato_timer(timer: &TomatoTimer) -> Result<String, WebhookError> {
+    let phase = Phase::of(timer);
+    let now = Utc::now();
+    let previous = *CURRENT_PHASE.lock().unwrap();
+    if previous.map(|previous| previous.phase) == Some(phase) && !timer.done {
+        return Ok("No phase change".to_string());
+    }
+
+    let result = split_current_entry(!timer.done && !timer.is_work).await?;
+
+    // A work phase of this timer was completed: either the timer moved on to a break,
+    // or it finished after the full work duration. A timer stopped mid-work earns nothing.
+    if let Some(CurrentPhase { phase: previous, started_at }) = previous
+        && previous.is_work
+        && previous.timer_id == phase.timer_id
+        && if timer.done {
+            (now - started_at).num_milliseconds() + COMPLETION_TOLERANCE_MS >= timer.work_duration
+        } else {
+            !timer.is_work
+        }
+    {
+        let bonus = completion_bonus_ms();
+        if bonus != 0 {
+            credit_leisure(bonus);
+            println!("[POMODORO] Cycle {} completed -> balance {:+}ms", previous.cycle, bonus);
+        }
+    }
+
+    let started_at = match previous {
+        Some(previous) if previous.phase == phase => previous.started_at,
+        _ => now,
+    };
+    *CURRENT_PHASE.lock().unwrap() = if timer.done {
+        None
+    } else {
+        Some(CurrentPhase { phase, started_at })
+    };
+    Ok(result)
+}
@@ -0,0 +1,102 @@
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::{
+    jobs::error::WebhookError,
+    routes::marvin_webhooks::toggl_client_from_env,
+    sync::origin,
+    toggl_api::responses::TimeEntry,
+    tracking::leisure::revert_leisure_between,
+};
+
+/// A Toggl entry marvinhooks stopped for a Marvin task, with what went into its leisure accrual.
+#[derive(Debug, Clone)]
+struct RecentStop {
+    entry_id: i64,
+    workspace_id: i64,
+    tags: Option<Vec<String>>,
+    productivity_override: Option<bool>,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    stopped_at: DateTime<Utc>,
+}
+
+/// Last stop per Marvin task ID
+static RECENT_STOPS: LazyLock<Mutex<HashMap<String, RecentStop>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// How long after a stop a start of the same task resumes the stopped entry
+/// (`RESUME_GRACE_SECS`, default 0 = never).
+fn grace_secs() -> i64 {
+    match env::var("RESUME_GRACE_SECS") {
+        Ok(val) => val.parse().unwrap_or_else(|_| {
+            eprintln!("RESUME_GRACE_SECS is not a number, not resuming entries");
+            0
+        }),
+        Err(_) => 0,
+    }
+}
+
+/// Remember that `entry` was just stopped for Marvin task `task_id`.
+pub fn record_stop(task_id: &str, entry: &TimeEntry, productivity_override: Option<bool>) {
+    if grace_secs() <= 0 {
+        return;
+    }
+    let start = entry.start.parse::<DateTime<Utc>>();
+    let stop = entry.stop.as_deref().map(str::parse::<DateTime<Utc>>);
+    let (start, stop) = match (start, stop, entry.workspace_id) {
+        (Ok(start), Some(Ok(stop)), Some(_)) => (start, stop),
+        _ => return,
+    };
+    let now = Utc::now();
+    let mut stops = RECENT_STOPS.lock().unwrap();
+    stops.retain(|_, recent| (now - recent.stopped_at).num_seconds() < grace_secs());
+    stops.insert(
+        task_id.to_string(),
+        RecentStop {
+            entry_id: entry.id,
+            workspace_id: entry.workspace_id.unwrap_or_default(),
+            tags: entry.tags.clone(),
+            productivity_override,
+            start,
+            stop,
+            stopped_at: now,
+        },
+    );
+}
+
+/// If Marvin task `task_id` was stopped within the grace window and nothing else
+/// is running, resume the stopped entry instead of starting a new one.
+/// The leisure accrued at the stop is taken back; the merged entry is accounted
+/// for in full when it stops again. Returns the resumed entry.
+/// Callers must hold the tracking lock.
+pub async fn try_resume(task_id: &str) -> Result<Option<TimeEntry>, WebhookError> {
+    let recent = match RECENT_STOPS.lock().unwrap().remove(task_id) {
+        Some(recent) if (Utc::now() - recent.stopped_at).num_seconds() < grace_secs() => recent,
+        _ => return Ok(None),
+    };
+
+    let toggl_client = toggl_client_from_env()?;
+    if toggl_client.get_current_time_entry().await?.is_some() {
+        return Ok(None);
+    }
+
+    let entry = toggl_client
+        .restart_time_entry(recent.workspace_id, recent.entry_id)
+        .await?;
+    origin::mark_running(entry.id);
+    // Undo the stop's accrual for each day it was booked on
+    revert_leisure_between(
+        recent.tags.as_deref(),
+        recent.productivity_override,
+        recent.start,
+        recent.stop,
+    );
+
+    println!("[RESUME] Resumed entry {} for task {}", entry.id, task_id);
+    Ok(Some(entry))
+}
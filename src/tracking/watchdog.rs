@@ -0,0 +1,255 @@
+use chrono::{DateTime, Duration as ChronoDuration, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, io,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+use crate::{
+    api::requests::SetRemindersRequest,
+    jobs::error::WebhookError,
+    models::reminders::Reminder,
+    routes::marvin_webhooks::{marvin_client_from_env, toggl_client_from_env},
+    store::json,
+    toggl_api::responses::TimeEntry,
+    tracking::{days::user_timezone, lock::TRACKING_LOCK},
+};
+
+/// File (inside the data directory) auto-stops are recorded in.
+const AUTO_STOP_FILE: &str = "auto_stops.json";
+/// Default time between checks of the running entry
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+/// Number of auto-stops kept around for `GET /auto-stops`
+const HISTORY: usize = 100;
+
+/// Limits on how long an entry may run, from the environment:
+///   `AUTO_STOP_MAX_MINUTES`          global maximum duration
+///   `AUTO_STOP_PROJECT_MAX_MINUTES`  JSON object of Toggl project ID -> maximum, e.g. {"123": 90}
+///   `WORKDAY_END`                    time (HH:MM) in the user's timezone no entry may run past
+#[derive(Debug, Clone, Default)]
+struct AutoStopConfig {
+    max_minutes: Option<i64>,
+    project_max_minutes: HashMap<i64, i64>,
+    workday_end: Option<NaiveTime>,
+    /// Create a Marvin reminder for each auto-stop (`AUTO_STOP_NOTIFY`)
+    notify: bool,
+}
+
+impl AutoStopConfig {
+    fn from_env() -> Self {
+        let max_minutes = env::var("AUTO_STOP_MAX_MINUTES").ok().and_then(|val| {
+            val.parse().map_err(|_| eprintln!("AUTO_STOP_MAX_MINUTES is not a number")).ok()
+        });
+        let project_max_minutes = match env::var("AUTO_STOP_PROJECT_MAX_MINUTES") {
+            Ok(val) => match serde_json::from_str::<HashMap<String, i64>>(&val) {
+                Ok(limits) => limits
+                    .into_iter()
+                    .filter_map(|(project_id, minutes)| Some((project_id.parse().ok()?, minutes)))
+                    .collect(),
+                Err(err) => {
+                    eprintln!("AUTO_STOP_PROJECT_MAX_MINUTES is not valid JSON: {}", err);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+        let workday_end = env::var("WORKDAY_END").ok().and_then(|val| {
+            NaiveTime::parse_from_str(&val, "%H:%M")
+                .map_err(|_| eprintln!("WORKDAY_END is not a HH:MM time"))
+                .ok()
+        });
+        let notify = env::var("AUTO_STOP_NOTIFY").is_ok_and(|val| val == "true" || val == "1");
+
+        AutoStopConfig { max_minutes, project_max_minutes, workday_end, notify }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_minutes.is_some() || !self.project_max_minutes.is_empty() || self.workday_end.is_some()
+    }
+
+    /// The latest time `entry` may run until, and why.
+    fn cap(&self, entry: &TimeEntry, start: DateTime<Utc>) -> Option<(DateTime<Utc>, String)> {
+        let mut caps: Vec<(DateTime<Utc>, String)> = vec![];
+
+        let max_minutes = entry
+            .project_id
+            .and_then(|project_id| self.project_max_minutes.get(&project_id).copied())
+            .or(self.max_minutes);
+        if let Some(minutes) = max_minutes {
+            caps.push((start + ChronoDuration::minutes(minutes), format!("ran longer than {} minutes", minutes)));
+        }
+
+        // The first end of a workday after the entry started
+        if let Some(end) = self.workday_end {
+            let tz = user_timezone();
+            let local_start = start.with_timezone(&tz);
+            let mut workday_end = local_start.date_naive().and_time(end).and_local_timezone(tz).earliest();
+            if workday_end.is_some_and(|workday_end| workday_end <= local_start) {
+                workday_end = (local_start.date_naive() + ChronoDuration::days(1))
+                    .and_time(end)
+                    .and_local_timezone(tz)
+                    .earliest();
+            }
+            if let Some(workday_end) = workday_end {
+                caps.push((workday_end.with_timezone(&Utc), format!("ran past the end of the workday ({})", end)));
+            }
+        }
+
+        caps.into_iter().min_by_key(|(cap, _)| *cap)
+    }
+}
+
+/// A running entry the watchdog stopped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoStop {
+    pub entry_id: i64,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub project_id: Option<i64>,
+    pub start: String,
+    /// Where the entry was trimmed to, after the entry policy rounded it
+    pub stop: String,
+    pub reason: String,
+    /// Change to the leisure balance (ms) for the trimmed duration
+    pub leisure_change: i64,
+    /// Unix milliseconds
+    pub detected_at: i64,
+}
+
+/// Durable log of auto-stops.
+pub struct AutoStopLog {
+    stops: Mutex<Vec<AutoStop>>,
+}
+
+pub static AUTO_STOPS: LazyLock<AutoStopLog> = LazyLock::new(|| AutoStopLog {
+    stops: Mutex::new(json::load_json(AUTO_STOP_FILE).unwrap_or_default()),
+});
+
+impl AutoStopLog {
+    fn persist(stops: &Vec<AutoStop>) -> io::Result<()> {
+        json::save_json(AUTO_STOP_FILE, stops)
+    }
+
+    fn add(&self, stop: AutoStop) {
+        let mut stops = self.stops.lock().unwrap();
+        stops.push(stop);
+        let excess = stops.len().saturating_sub(HISTORY);
+        stops.drain(..excess);
+        if let Err(err) = Self::persist(&stops) {
+            eprintln!("[AUTO STOP] Could not persist auto-stops: {}", err);
+        }
+    }
+
+    pub fn list(&self) -> Vec<AutoStop> {
+        self.stops.lock().unwrap().clone()
+    }
+}
+
+/// Let the user know in Marvin that an entry was stopped for them.
+async fn notify(stop: &AutoStop) -> Result<(), WebhookError> {
+    let marvin_client = marvin_client_from_env()?;
+    let request = SetRemindersRequest {
+        reminders: vec![Reminder {
+            time: Utc::now().timestamp(),
+            offset: 0,
+            reminder_id: format!("marvinhooks-auto-stop-{}", stop.entry_id),
+            reminder_type: "M".to_string(),
+            title: Some(format!(
+                "Stopped '{}': it {}",
+                stop.description.as_deref().unwrap_or("(no description)"),
+                stop.reason
+            )),
+            snooze: None,
+            auto_snooze: None,
+            can_track: false,
+        }],
+    };
+    marvin_client.set_reminders(&request).await?;
+    Ok(())
+}
+
+/// Stop the running entry if it went past its cap, trimming it to the cap.
+/// Callers must hold the tracking lock.
+async fn check_running_entry(config: &AutoStopConfig) -> Result<Option<AutoStop>, WebhookError> {
+    let toggl_client = toggl_client_from_env()?;
+    let entry = match toggl_client.get_current_time_entry().await? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let start: DateTime<Utc> = match entry.start.parse() {
+        Ok(start) => start,
+        Err(_) => {
+            return Err(WebhookError::DataError(format!("Invalid start time '{}'", entry.start)));
+        }
+    };
+    let (cap, reason) = match config.cap(&entry, start) {
+        Some((cap, reason)) if cap <= Utc::now() => (cap, reason),
+        _ => return Ok(None),
+    };
+
+    println!("[AUTO STOP] Entry {} {}, stopping it at {}", entry.id, reason, cap);
+    let stopped = toggl_client.stop_time_entry_at(&entry, cap, None).await?;
+    let (stop, reason) = match stopped.stop {
+        Some(stop) => (stop, reason),
+        None => (cap, format!("{}, and was too short to keep", reason)),
+    };
+
+    Ok(Some(AutoStop {
+        entry_id: entry.id,
+        description: entry.description.clone(),
+        project_id: entry.project_id,
+        start: entry.start.clone(),
+        stop: stop.to_rfc3339(),
+        reason,
+        leisure_change: stopped.leisure_change,
+        detected_at: Utc::now().timestamp_millis(),
+    }))
+}
+
+/// Check the running Toggl entry every `AUTO_STOP_INTERVAL_SECS` seconds and stop
+/// entries that were forgotten. Does nothing unless a limit is configured.
+pub async fn run_auto_stop() {
+    let config = AutoStopConfig::from_env();
+    if !config.is_enabled() {
+        return;
+    }
+    let interval_secs = match env::var("AUTO_STOP_INTERVAL_SECS") {
+        Ok(val) => match val.parse() {
+            Ok(secs) if secs > 0 => secs,
+            _ => {
+                eprintln!("AUTO_STOP_INTERVAL_SECS is not a positive number, using the default");
+                DEFAULT_INTERVAL_SECS
+            }
+        },
+        Err(_) => DEFAULT_INTERVAL_SECS,
+    };
+    println!("[AUTO STOP] Watching running entries every {}s: {:?}", interval_secs, config);
+
+    loop {
+        sleep(Duration::from_secs(interval_secs)).await;
+
+        let stopped = {
+            let _guard = TRACKING_LOCK.lock().await;
+            check_running_entry(&config).await
+        };
+        let stopped = match stopped {
+            Ok(Some(stopped)) => stopped,
+            Ok(None) => continue,
+            Err(err) => {
+                println!("[AUTO STOP] Check failed: {}", err.chain().join(": "));
+                continue;
+            }
+        };
+
+        if config.notify
+            && let Err(err) = notify(&stopped).await
+        {
+            println!("[AUTO STOP] Could not create Marvin reminder: {}", err.chain().join(": "));
+        }
+        AUTO_STOPS.add(stopped);
+    }
+}